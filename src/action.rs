@@ -1,82 +1,705 @@
 use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ffi::OsString;
 use std::fmt;
-use std::process::Command;
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use anyhow::{bail, Result};
 use base64::{prelude::BASE64_STANDARD, Engine as _};
-use regex::Regex;
+use rand::Rng;
+use regex::{Regex, RegexBuilder};
 use serde::{Deserialize, Serialize};
-use tracing::error;
-use tsclientlib::{Connection, MessageTarget, TextMessageTargetMode};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+use tracing::{debug, error, info, warn};
+use tsclientlib::{MessageTarget, TextMessageTargetMode};
 
-use crate::{Bot, Message};
+use crate::connection::BotConnection;
+use crate::permission;
+use crate::rate_limit;
+use crate::script::RhaiScript;
+use crate::template::{self, Captures, Template};
+use crate::timer;
+use crate::wasm::WasmPlugin;
+use crate::{Bot, ChannelDefinition, Message};
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct ActionDefinition {
+	/// The name of this action, used to restrict it in the
+	/// `action_permissions` settings.
+	pub name: Option<String>,
+
 	// Matcher
-	/// Plain string
-	pub contains: Option<String>,
-	/// Regex
-	pub regex: Option<String>,
-	/// Check the chat mode for the message: Either `server`, `channel`,
-	/// `client` or `poke`.
-	pub chat: Option<String>,
+	#[serde(flatten)]
+	pub matcher: MatcherDefinition,
 
 	// Reaction
 	/// A simple string response.
-	pub response: Option<String>,
+	///
+	/// Can contain placeholders like `{invoker.name}` or `{1}` for capture
+	/// groups of the regex. Can also be a list of responses or a table of
+	/// responses and their weights, then a random response is picked.
+	pub response: Option<ResponseDefinition>,
+	/// Do not repeat the last this many responses if there are multiple
+	/// responses.
+	pub no_repeat: Option<usize>,
+
+	// Cooldown
+	/// Do not trigger this action again for some time, e.g. `30s` or `5m`.
+	pub cooldown: Option<String>,
+	/// If the cooldown is per `action`, per `user` or per `target` (the chat
+	/// where the message was sent).
+	///
+	/// Defaults to `action`.
+	pub cooldown_per: Option<String>,
+	/// What happens if the action matches while it is cooling down: Either
+	/// `silent` to not respond or `next` to try the next action.
+	///
+	/// Defaults to `silent`.
+	pub cooldown_behavior: Option<String>,
 	/// Execute program
 	pub command: Option<String>,
 	/// Execute command in a shell
 	pub shell: Option<String>,
+	/// Run a Rhai script from this file.
+	///
+	/// The path is relative to the file which contains the action.
+	pub script: Option<String>,
+	/// Load a WebAssembly plugin from this file and let it handle messages.
+	///
+	/// The path is relative to the file which contains the action.
+	pub wasm: Option<String>,
+	/// Kill a command, shell or script after this many seconds.
+	///
+	/// Defaults to `script_timeout` from the settings.
+	pub timeout: Option<u64>,
+	/// Truncate the output of a command or shell to this many bytes.
+	///
+	/// Defaults to `script_max_output` from the settings.
+	pub max_output: Option<usize>,
+	/// The output of a command or shell is a JSON list of operations instead
+	/// of a message.
+	pub json_output: Option<bool>,
+}
+
+/// One or more responses.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ResponseDefinition {
+	Single(String),
+	/// Pick a random response.
+	List(Vec<String>),
+	/// Pick a random response, responses with a higher weight are picked more
+	/// often.
+	Weighted(BTreeMap<String, u32>),
+}
+
+/// The conditions of an action, which can be nested with `not`, `any_of` and
+/// `all_of`.
+///
+/// All conditions have to match. The conditions of an action are written
+/// directly in the action.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MatcherDefinition {
+	/// Plain string
+	pub contains: Option<String>,
+	/// Regex
+	pub regex: Option<String>,
+	/// How `contains` matches the message: Either `word`, `substring`,
+	/// `exact` or `prefix`.
+	///
+	/// Defaults to `word`.
+	#[serde(rename = "match")]
+	pub match_mode: Option<String>,
+	/// Ignore the case for `contains` or `regex`.
+	pub ignore_case: Option<bool>,
+	/// Only match messages from users with one of these base64 encoded uids.
+	pub from_uid: Option<Vec<String>>,
+	/// Only match messages from users whose name matches this regex.
+	pub from_name: Option<String>,
+	/// Only match messages from users in one of these server groups.
+	pub from_server_group: Option<Vec<u64>>,
+	/// Check the chat mode for the message: Either `server`, `channel`,
+	/// `client` or `poke`.
+	pub chat: Option<String>,
+	/// Only match if these conditions do not match.
+	pub not: Option<Box<MatcherDefinition>>,
+	/// Match if one of these conditions matches.
+	pub any_of: Option<Vec<MatcherDefinition>>,
+	/// Match if all of these conditions match.
+	pub all_of: Option<Vec<MatcherDefinition>>,
 }
 
 #[derive(Default, Debug)]
 pub struct ActionList(pub Vec<Action>);
 
+/// Actions which are triggered by clients instead of messages.
+#[derive(Default, Debug)]
+pub struct EventActions {
+	pub join: ActionList,
+	pub leave: ActionList,
+	pub channel_enter: ActionList,
+	pub channel_leave: ActionList,
+}
+
+/// What triggers an action.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+	Message,
+	Join,
+	Leave,
+	ChannelEnter,
+	ChannelLeave,
+	Timer,
+}
+
 #[derive(Default, Debug)]
 pub struct Action {
+	/// If set, the invoker needs the permission with this name.
+	///
+	/// Commands of plugins are looked up in `permissions`, all other actions
+	/// in `action_permissions`.
+	pub name: Option<String>,
 	/// All matchers have to match for the reaction to be executed.
 	pub matchers: Vec<Matcher>,
 	/// If empty and this action matches, no action will be executed.
 	pub reaction: Option<Reaction>,
+	pub cooldown: Option<Cooldown>,
+}
+
+#[derive(Debug)]
+pub struct Cooldown {
+	pub duration: Duration,
+	pub per: CooldownPer,
+	/// If the next action should be tried while cooling down.
+	pub next: bool,
+	/// Identifies the action in [`Cooldowns`], so the cooldown is kept when
+	/// the actions are reloaded.
+	///
+	/// This is the name of the action or its definition if it has no name.
+	pub id: String,
+}
+
+/// When actions stop cooling down, by action id and user or target.
+pub type Cooldowns = RefCell<HashMap<(String, String), Instant>>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CooldownPer {
+	Action,
+	User,
+	Target,
 }
 
 #[derive(Clone, Debug)]
 pub enum Matcher {
+	Contains(Contains),
 	Regex(Regex),
 	/// If this is `None`, it means poke.
 	Mode(Option<TextMessageTargetMode>),
+	/// The invoker has one of these base64 encoded uids.
+	Uid(Vec<String>),
+	/// The name of the invoker matches this regex.
+	Name(Regex),
+	/// The invoker is in one of these server groups.
+	ServerGroup(Vec<u64>),
+	Not(Box<Matcher>),
+	/// At least one of the matchers matches.
+	Any(Vec<Matcher>),
+	/// All matchers match.
+	All(Vec<Matcher>),
+}
+
+/// A plain string which has to be in the message.
+#[derive(Clone, Debug)]
+pub struct Contains {
+	pub text: String,
+	pub mode: MatchMode,
+	pub ignore_case: bool,
+	/// The regex which is generated from the other fields.
+	pub regex: Regex,
+}
+
+/// Where a [`Contains`] matcher matches the message.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MatchMode {
+	/// Whole words anywhere in the message.
+	#[default]
+	Word,
+	/// Anywhere in the message.
+	Substring,
+	/// The whole message.
+	Exact,
+	/// The start of the message.
+	Prefix,
+}
+
+/// The operations of a script or `None` if the next action should be tried.
+pub type ScriptFuture =
+	Pin<Box<dyn Future<Output = Option<Vec<Operation>>> + Send>>;
+
+/// Something a script asks the bot to do.
+///
+/// Scripts with `json_output` print a JSON list of operations, the output of
+/// other scripts is sent as message.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Operation {
+	/// Send a message, by default to the chat of the message which started
+	/// the script.
+	Send {
+		target: Option<OperationTarget>,
+		/// The client id if the target is `client`, defaults to the invoker.
+		client: Option<u16>,
+		message: String,
+	},
+	/// Poke a client, defaults to the invoker.
+	Poke {
+		client: Option<u16>,
+		message: String,
+	},
+	/// Move the bot into a channel, given by id or by its path.
+	Move { channel: ChannelDefinition },
+	/// Kick a client from the channel or from the server.
+	Kick {
+		client: u16,
+		#[serde(default)]
+		from: KickFrom,
+		#[serde(default)]
+		reason: String,
+	},
+	/// Ignore the following operations.
+	Stop,
+	/// Try the next action after performing the operations.
+	Continue,
+	/// Perform operations after some time, used by wasm plugins.
+	#[serde(skip_deserializing)]
+	Later {
+		delay: Duration,
+		operations: Vec<Operation>,
+	},
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OperationTarget {
+	Server,
+	Channel,
+	Client,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KickFrom {
+	#[default]
+	Channel,
+	Server,
+}
+
+pub enum Response<'a> {
+	/// A message which should be sent. Nothing is sent if it is empty.
+	Text(Cow<'a, str>),
+	/// A script was started, its output should be sent when it finishes.
+	Script(ScriptFuture),
+}
+
+/// The result of searching a reaction for a message.
+pub enum Handled<'a> {
+	/// No response should be sent.
+	Nothing,
+	Response(Cow<'a, str>),
+	/// The invoker is not allowed to use the matching action.
+	NotAllowed,
+	/// The action with this index started a script.
+	///
+	/// If the script fails, the message should be handled again, starting
+	/// with the next action.
+	Pending(usize, ScriptFuture),
 }
 
-type ReactionFunction = Box<
-	dyn for<'a> Fn(&Bot, &mut Connection, &'a Message) -> Option<Cow<'a, str>>
-		+ Send
-		+ Sync,
->;
 pub enum Reaction {
-	Plain(String),
-	Command(String),
-	Shell(String),
-	Function(ReactionFunction),
+	Plain(Template),
+	Random(RandomResponse),
+	Command(Script),
+	Shell(Script),
+	Script(RhaiScript),
+	Wasm(WasmPlugin),
+	/// Run a command of a plugin.
+	Plugin {
+		/// The index of the plugin in the bot.
+		plugin: usize,
+		command: String,
+	},
+}
+
+/// Multiple responses where a random one is picked.
+#[derive(Debug)]
+pub struct RandomResponse {
+	/// Responses and their weights.
+	responses: Vec<(Template, u32)>,
+	/// How many of the last responses should not be repeated.
+	no_repeat: usize,
+	/// The indices of the last picked responses.
+	last: Mutex<VecDeque<usize>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Script {
+	pub command: String,
+	/// If `None`, the default from the settings is used.
+	pub timeout: Option<Duration>,
+	/// If `None`, the default from the settings is used.
+	pub max_output: Option<usize>,
+	/// Parse the output as a list of [`Operation`]s.
+	pub json_output: bool,
+}
+
+/// The information about a message which is passed to scripts.
+///
+/// It is written as JSON to stdin and set as `SIMPLEBOT_*` environment
+/// variables.
+#[derive(Clone, Debug, Serialize)]
+pub struct ScriptInput {
+	/// The chat mode (server|channel|client|poke).
+	pub target: &'static str,
+	pub message: String,
+	pub invoker: ScriptInvoker,
+	/// The channel of the invoker if it is known.
+	pub channel: Option<ScriptChannel>,
+	/// The capture groups of the regex.
+	pub captures: BTreeMap<String, String>,
+	/// The name of the bot.
+	pub bot_name: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ScriptInvoker {
+	pub id: u16,
+	pub name: String,
+	/// Base64 encoded uid, not set for global server messages.
+	pub uid: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ScriptChannel {
+	pub id: u64,
+	pub name: String,
 }
 
 impl fmt::Debug for Reaction {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		match self {
-			Reaction::Plain(s) => write!(f, "Reaction::Plain({})", s),
-			Reaction::Command(s) => write!(f, "Reaction::Command({})", s),
-			Reaction::Shell(s) => write!(f, "Reaction::Shell({})", s),
-			Reaction::Function(_) => write!(f, "Reaction::Function()"),
+			Reaction::Plain(s) => {
+				write!(f, "Reaction::Plain({})", s.as_str())
+			}
+			Reaction::Random(r) => {
+				write!(f, "Reaction::Random(")?;
+				for (i, (s, weight)) in r.responses.iter().enumerate() {
+					if i != 0 {
+						write!(f, ", ")?;
+					}
+					write!(f, "{}: {}", s.as_str(), weight)?;
+				}
+				write!(f, ")")
+			}
+			Reaction::Command(s) => {
+				write!(f, "Reaction::Command({})", s.command)
+			}
+			Reaction::Shell(s) => write!(f, "Reaction::Shell({})", s.command),
+			Reaction::Script(s) => write!(f, "Reaction::Script({:?})", s),
+			Reaction::Wasm(p) => write!(f, "Reaction::Wasm({:?})", p),
+			Reaction::Plugin { plugin, command } => {
+				write!(f, "Reaction::Plugin({}, {})", plugin, command)
+			}
 		}
 	}
 }
 
 impl ActionDefinition {
-	pub fn to_action(&self) -> Result<Action> {
+	pub(crate) fn to_action(&self) -> Result<Action> {
+		self.to_action_in(Path::new(""))
+	}
+
+	/// Create an action, files of scripts are relative to `base_dir`.
+	pub(crate) fn to_action_in(&self, base_dir: &Path) -> Result<Action> {
 		// Condition
-		let mut res = Action::default();
+		let mut res = Action {
+			name: self.name.clone(),
+			matchers: self.matcher.to_matchers()?,
+			..Default::default()
+		};
+		// Capture groups in responses refer to contains or regex
+		let action_regex = res.matchers.iter().find_map(|m| match m {
+			Matcher::Contains(c) => Some(c.regex.clone()),
+			Matcher::Regex(r) => Some(r.clone()),
+			_ => None,
+		});
+
+		// Reaction
+		let mut counter = 0;
+		if let Some(r) = &self.response {
+			let mut reaction = r.to_reaction(action_regex.as_ref())?;
+			if let Reaction::Random(r) = &mut reaction {
+				r.no_repeat = self.no_repeat.unwrap_or_default();
+			}
+			res.reaction = Some(reaction);
+			counter += 1;
+		}
+		if let Some(c) = &self.command {
+			res.reaction = Some(Reaction::Command(self.to_script(c)));
+			counter += 1;
+		}
+		if let Some(s) = &self.shell {
+			res.reaction = Some(Reaction::Shell(self.to_script(s)));
+			counter += 1;
+		}
+		if let Some(s) = &self.script {
+			let mut script = RhaiScript::compile(&base_dir.join(s))?;
+			script.timeout = self.timeout.map(Duration::from_secs);
+			res.reaction = Some(Reaction::Script(script));
+			counter += 1;
+		}
+		if let Some(w) = &self.wasm {
+			let plugin = WasmPlugin::load(&base_dir.join(w))?;
+			res.reaction = Some(Reaction::Wasm(plugin));
+			counter += 1;
+		}
+
+		if counter > 1 {
+			bail!(
+				"Only one reaction (response, command, shell, script or wasm) \
+				 is allowed."
+			);
+		}
+		res.cooldown = self.to_cooldown()?;
+
+		if self.no_repeat.is_some()
+			&& !matches!(res.reaction, Some(Reaction::Random(_)))
+		{
+			bail!("no_repeat can only be used with multiple responses");
+		}
+		if self.timeout.is_some()
+			&& self.command.is_none()
+			&& self.shell.is_none()
+			&& self.script.is_none()
+		{
+			bail!("timeout can only be used with command, shell or script");
+		}
+		if (self.max_output.is_some() || self.json_output.is_some())
+			&& self.command.is_none()
+			&& self.shell.is_none()
+		{
+			bail!(
+				"max_output and json_output can only be used with command or \
+				 shell"
+			);
+		}
+
+		Ok(res)
+	}
+}
+
+impl ResponseDefinition {
+	/// Escape braces in responses which are not valid templates.
+	///
+	/// Braces in responses were literal before placeholders existed. Returns
+	/// `true` if a response was changed.
+	pub(crate) fn escape_old_responses(&mut self, regex: Option<&str>) -> bool {
+		let regex = regex.and_then(|r| Regex::new(r).ok());
+		let regex = regex.as_ref();
+		let escape = |s: &str| -> Option<String> {
+			if Template::parse(s, regex).is_ok() {
+				return None;
+			}
+			let escaped = template::escape(s);
+			Template::parse(&escaped, regex).ok().map(|_| escaped)
+		};
+		let mut changed = false;
+		match self {
+			ResponseDefinition::Single(s) => {
+				if let Some(e) = escape(s) {
+					*s = e;
+					changed = true;
+				}
+			}
+			ResponseDefinition::List(l) => {
+				for s in l {
+					if let Some(e) = escape(s) {
+						*s = e;
+						changed = true;
+					}
+				}
+			}
+			ResponseDefinition::Weighted(w) => {
+				*w = std::mem::take(w)
+					.into_iter()
+					.map(|(s, weight)| match escape(&s) {
+						Some(e) => {
+							changed = true;
+							(e, weight)
+						}
+						None => (s, weight),
+					})
+					.collect();
+			}
+		}
+		changed
+	}
+
+	/// Add an alternative response.
+	pub(crate) fn push(&mut self, response: String) {
+		match self {
+			ResponseDefinition::Single(s) => {
+				*self = ResponseDefinition::List(vec![s.clone(), response]);
+			}
+			ResponseDefinition::List(l) => l.push(response),
+			// Keep the weight if the response exists already
+			ResponseDefinition::Weighted(w) => {
+				w.entry(response).or_insert(1);
+			}
+		}
+	}
+
+	fn to_reaction(&self, regex: Option<&Regex>) -> Result<Reaction> {
+		let responses = match self {
+			ResponseDefinition::Single(s) => {
+				return Ok(Reaction::Plain(Template::parse(s, regex)?));
+			}
+			ResponseDefinition::List(l) => l
+				.iter()
+				.map(|s| Ok((Template::parse(s, regex)?, 1)))
+				.collect::<Result<Vec<_>>>()?,
+			ResponseDefinition::Weighted(w) => w
+				.iter()
+				.map(|(s, weight)| Ok((Template::parse(s, regex)?, *weight)))
+				.collect::<Result<Vec<_>>>()?,
+		};
+		if responses.iter().all(|(_, weight)| *weight == 0) {
+			bail!("At least one response needs a weight above zero");
+		}
+		Ok(Reaction::Random(RandomResponse {
+			responses,
+			no_repeat: 0,
+			last: Default::default(),
+		}))
+	}
+}
+
+impl From<String> for ResponseDefinition {
+	fn from(s: String) -> Self { ResponseDefinition::Single(s) }
+}
+
+impl From<&str> for ResponseDefinition {
+	fn from(s: &str) -> Self { ResponseDefinition::Single(s.into()) }
+}
+
+impl RandomResponse {
+	/// Pick a random response.
+	///
+	/// Avoids the last `no_repeat` responses if possible.
+	pub fn pick(&self) -> &Template {
+		let mut last = self.last.lock().unwrap();
+		let candidates = self
+			.responses
+			.iter()
+			.enumerate()
+			.filter(|(i, (_, weight))| *weight > 0 && !last.contains(i))
+			.collect::<Vec<_>>();
+		let candidates = if candidates.is_empty() {
+			// All responses were used recently
+			self.responses.iter().enumerate().collect()
+		} else {
+			candidates
+		};
+
+		// Weights can add up to more than u32::MAX
+		let total: u64 =
+			candidates.iter().map(|(_, (_, w))| u64::from(*w)).sum();
+		let mut n = rand::thread_rng().gen_range(0..total.max(1));
+		let mut picked = candidates[0].0;
+		for (i, (_, weight)) in &candidates {
+			let weight = u64::from(*weight);
+			if n < weight {
+				picked = *i;
+				break;
+			}
+			n -= weight;
+		}
+
+		if self.no_repeat > 0 {
+			if last.len() >= self.no_repeat {
+				last.pop_front();
+			}
+			last.push_back(picked);
+		}
+		&self.responses[picked].0
+	}
+}
+
+impl ActionDefinition {
+	fn to_cooldown(&self) -> Result<Option<Cooldown>> {
+		let duration = match &self.cooldown {
+			Some(c) => timer::parse_duration(c)?,
+			None => {
+				if self.cooldown_per.is_some()
+					|| self.cooldown_behavior.is_some()
+				{
+					bail!(
+						"cooldown_per and cooldown_behavior can only be used \
+						 with cooldown"
+					);
+				}
+				return Ok(None);
+			}
+		};
+		let per = match self.cooldown_per.as_deref() {
+			Some("action") | None => CooldownPer::Action,
+			Some("user") => CooldownPer::User,
+			Some("target") => CooldownPer::Target,
+			Some(s) => bail!(
+				"cooldown_per must be action, user or target. '{}' is not \
+				 allowed.",
+				s
+			),
+		};
+		let next = match self.cooldown_behavior.as_deref() {
+			Some("silent") | None => false,
+			Some("next") => true,
+			Some(s) => bail!(
+				"cooldown_behavior must be silent or next. '{}' is not \
+				 allowed.",
+				s
+			),
+		};
+		Ok(Some(Cooldown {
+			duration,
+			per,
+			next,
+			id: self.name.clone().unwrap_or_else(|| format!("{:?}", self)),
+		}))
+	}
+
+	fn to_script(&self, command: &str) -> Script {
+		Script {
+			command: command.to_string(),
+			timeout: self.timeout.map(Duration::from_secs),
+			max_output: self.max_output,
+			json_output: self.json_output.unwrap_or(false),
+		}
+	}
+}
+
+impl MatcherDefinition {
+	pub(crate) fn to_matchers(&self) -> Result<Vec<Matcher>> {
+		let ignore_case = self.ignore_case.unwrap_or(false);
+		let mut res = Vec::new();
 		if let Some(contains) = &self.contains {
 			if let Some(matches) = &self.regex {
 				bail!(
@@ -86,28 +709,38 @@ impl ActionDefinition {
 					matches
 				);
 			}
-			// Only match string at word boundaries
-			// Add \b only if the first/last character is alphabetix.
-			let mut regex = regex::escape(contains);
-			if regex
-				.chars()
-				.next()
-				.map(|c| c.is_alphabetic())
-				.unwrap_or(false)
-			{
-				regex = format!(r"\b{}", regex);
-			}
-			if regex
-				.chars()
-				.last()
-				.map(|c| c.is_alphabetic())
-				.unwrap_or(false)
-			{
-				regex = format!(r"{}\b", regex);
-			}
-			res.matchers.push(Matcher::Regex(Regex::new(&regex)?));
+			let mode = match self.match_mode.as_deref() {
+				Some("word") | None => MatchMode::Word,
+				Some("substring") => MatchMode::Substring,
+				Some("exact") => MatchMode::Exact,
+				Some("prefix") => MatchMode::Prefix,
+				Some(s) => bail!(
+					"Match must be word, substring, exact or prefix. '{}' is \
+					 not allowed.",
+					s
+				),
+			};
+			let regex = RegexBuilder::new(&mode.regex(contains))
+				.case_insensitive(ignore_case)
+				.build()?;
+			res.push(Matcher::Contains(Contains {
+				text: contains.clone(),
+				mode,
+				ignore_case,
+				regex,
+			}));
 		} else if let Some(matches) = &self.regex {
-			res.matchers.push(Matcher::Regex(Regex::new(matches)?));
+			if self.match_mode.is_some() {
+				bail!("match can only be used with contains");
+			}
+			let regex = RegexBuilder::new(matches)
+				.case_insensitive(ignore_case)
+				.build()?;
+			res.push(Matcher::Regex(regex));
+		} else if self.match_mode.is_some() || self.ignore_case.is_some() {
+			bail!(
+				"match and ignore_case can only be used with contains or regex"
+			);
 		}
 
 		if let Some(chat) = &self.chat {
@@ -122,35 +755,124 @@ impl ActionDefinition {
 					s
 				),
 			};
-			res.matchers.push(Matcher::Mode(mode));
+			res.push(Matcher::Mode(mode));
 		}
 
-		// Reaction
-		let mut counter = 0;
-		if let Some(r) = &self.response {
-			res.reaction = Some(Reaction::Plain(r.to_string()));
-			counter += 1;
+		if let Some(uids) = &self.from_uid {
+			for uid in uids {
+				if BASE64_STANDARD.decode(uid).is_err() {
+					bail!("Invalid uid '{}' in from_uid", uid);
+				}
+			}
+			res.push(Matcher::Uid(uids.clone()));
 		}
-		if let Some(c) = &self.command {
-			res.reaction = Some(Reaction::Command(c.to_string()));
-			counter += 1;
+		if let Some(name) = &self.from_name {
+			res.push(Matcher::Name(Regex::new(name)?));
 		}
-		if let Some(s) = &self.shell {
-			res.reaction = Some(Reaction::Shell(s.to_string()));
-			counter += 1;
+		if let Some(groups) = &self.from_server_group {
+			res.push(Matcher::ServerGroup(groups.clone()));
 		}
 
-		if counter > 1 {
-			bail!("Only one reaction (response, command or shell) is allowed.");
+		if let Some(not) = &self.not {
+			res.push(Matcher::Not(Box::new(not.to_matcher()?)));
+		}
+		if let Some(any) = &self.any_of {
+			if any.is_empty() {
+				bail!("any_of must not be empty");
+			}
+			let any =
+				any.iter().map(|m| m.to_matcher()).collect::<Result<_>>()?;
+			res.push(Matcher::Any(any));
+		}
+		if let Some(all) = &self.all_of {
+			if all.is_empty() {
+				bail!("all_of must not be empty");
+			}
+			let all =
+				all.iter().map(|m| m.to_matcher()).collect::<Result<_>>()?;
+			res.push(Matcher::All(all));
 		}
-
 		Ok(res)
 	}
+
+	/// Combine all conditions into a single matcher.
+	///
+	/// Fails if there are no conditions.
+	pub(crate) fn to_matcher(&self) -> Result<Matcher> {
+		let mut res = self.to_matchers()?;
+		if res.is_empty() {
+			bail!("Conditions in not, any_of and all_of must not be empty");
+		}
+		if res.len() == 1 {
+			Ok(res.pop().unwrap())
+		} else {
+			Ok(Matcher::All(res))
+		}
+	}
+}
+
+impl Cooldown {
+	fn key(&self, msg: &Message) -> (String, String) {
+		let scope = match self.per {
+			CooldownPer::Action => String::new(),
+			CooldownPer::User => rate_limit::user_key(&msg.invoker),
+			CooldownPer::Target => match msg.target {
+				MessageTarget::Client(id) | MessageTarget::Poke(id) => {
+					format!("client {}", id.0)
+				}
+				target => Reaction::get_target(&target).into(),
+			},
+		};
+		(self.id.clone(), scope)
+	}
+
+	/// Check if the action is still cooling down for this message.
+	pub fn is_cooling_down(
+		&self,
+		cooldowns: &Cooldowns,
+		msg: &Message,
+	) -> bool {
+		let now = Instant::now();
+		let mut cooldowns = cooldowns.borrow_mut();
+		cooldowns.retain(|_, until| *until > now);
+		cooldowns.contains_key(&self.key(msg))
+	}
+
+	/// Start the cooldown for this message.
+	pub fn start(&self, cooldowns: &Cooldowns, msg: &Message) {
+		let until = Instant::now() + self.duration;
+		cooldowns.borrow_mut().insert(self.key(msg), until);
+	}
+}
+
+impl Action {
+	/// Start the cooldown of this action after it handled a message.
+	pub fn start_cooldown(&self, bot: &Bot, msg: &Message) {
+		if let Some(c) = &self.cooldown {
+			c.start(&bot.cooldowns, msg);
+		}
+	}
+
+	/// Get the capture groups of the regex matchers of this action.
+	pub fn captures(&self, msg: &Message) -> Captures {
+		let mut res = Captures::new();
+		for m in &self.matchers {
+			match m {
+				Matcher::Contains(Contains { regex: r, .. })
+				| Matcher::Regex(r) => {
+					template::captures(r, msg.message, &mut res);
+				}
+				_ => {}
+			}
+		}
+		res
+	}
 }
 
 impl Matcher {
-	pub fn matches(&self, msg: &Message) -> bool {
+	pub fn matches(&self, con: &dyn BotConnection, msg: &Message) -> bool {
 		match self {
+			Matcher::Contains(c) => c.regex.is_match(msg.message),
 			Matcher::Regex(r) => r.is_match(msg.message),
 			Matcher::Mode(m) => match m {
 				Some(TextMessageTargetMode::Server) => {
@@ -167,7 +889,63 @@ impl Matcher {
 					matches!(msg.target, MessageTarget::Poke(_))
 				}
 			},
+			Matcher::Uid(uids) => match msg.invoker.uid {
+				Some(uid) => uids.contains(&BASE64_STANDARD.encode(&uid.0)),
+				None => false,
+			},
+			Matcher::Name(r) => r.is_match(msg.invoker.name),
+			Matcher::ServerGroup(groups) => match con.client(msg.invoker.id) {
+				Some(client) => {
+					client.server_groups.iter().any(|g| groups.contains(&g.0))
+				}
+				None => false,
+			},
+			Matcher::Not(m) => !m.matches(con, msg),
+			Matcher::Any(ms) => ms.iter().any(|m| m.matches(con, msg)),
+			Matcher::All(ms) => ms.iter().all(|m| m.matches(con, msg)),
+		}
+	}
+}
+
+impl MatchMode {
+	pub fn name(&self) -> &'static str {
+		match self {
+			MatchMode::Word => "word",
+			MatchMode::Substring => "substring",
+			MatchMode::Exact => "exact",
+			MatchMode::Prefix => "prefix",
+		}
+	}
+
+	/// Build a regex which matches `s` in this mode.
+	fn regex(&self, s: &str) -> String {
+		let mut regex = regex::escape(s);
+		match self {
+			MatchMode::Word => {
+				// Only match string at word boundaries
+				// Add \b only if the first/last character is alphabetix.
+				if regex
+					.chars()
+					.next()
+					.map(|c| c.is_alphabetic())
+					.unwrap_or(false)
+				{
+					regex = format!(r"\b{}", regex);
+				}
+				if regex
+					.chars()
+					.last()
+					.map(|c| c.is_alphabetic())
+					.unwrap_or(false)
+				{
+					regex = format!(r"{}\b", regex);
+				}
+			}
+			MatchMode::Substring => {}
+			MatchMode::Exact => regex = format!("^{}$", regex),
+			MatchMode::Prefix => regex = format!("^{}", regex),
 		}
+		regex
 	}
 }
 
@@ -193,124 +971,1042 @@ impl Reaction {
 		}
 	}
 
+	fn build_command(
+		&self,
+		s: &str,
+		msg: &Message,
+		input: &ScriptInput,
+	) -> Command {
+		let mut cmd;
+		if let Reaction::Command(_) = self {
+			// Split arguments at spaces
+			let mut split = s.split(' ');
+			cmd = Command::new(split.next().unwrap());
+			cmd.args(split);
+		} else {
+			// Shell
+			#[cfg(target_family = "unix")]
+			{
+				cmd = Command::new("sh");
+				cmd.arg("-c")
+					.arg(s)
+					// Program name
+					.arg("sh");
+			}
+
+			#[cfg(not(target_family = "unix"))]
+			{
+				// Windows is untested
+				cmd = Command::new("cmd");
+				cmd.arg("/C").arg(s);
+			}
+		}
+
+		// Arguments
+		cmd.arg(Self::get_target(&msg.target))
+			.arg(msg.message)
+			.arg(msg.invoker.name);
+		if let Some(uid) = &msg.invoker.uid {
+			cmd.arg(BASE64_STANDARD.encode(&uid.0));
+		}
+		remove_inherited_env(&mut cmd, std::env::vars_os());
+		cmd.envs(input.env())
+			.stdin(Stdio::piped())
+			.stdout(Stdio::piped())
+			.stderr(Stdio::inherit())
+			.kill_on_drop(true);
+		cmd
+	}
+
 	/// If `None` is returned, the next action should be tested.
 	pub fn execute<'a>(
 		&'a self,
 		bot: &Bot,
-		con: &mut Connection,
+		con: &mut dyn BotConnection,
 		msg: &'a Message,
-	) -> Option<Cow<'a, str>> {
+		captures: &Captures,
+	) -> Option<Response<'a>> {
 		match self {
-			Reaction::Plain(s) => Some(Cow::Borrowed(s.as_str())),
-			Reaction::Command(s) | Reaction::Shell(s) => {
-				let output;
-				if let Reaction::Command(_) = self {
-					// Split arguments at spaces
-					let mut split = s.split(' ');
-					let mut cmd = Command::new(split.next().unwrap());
-					cmd.args(split)
-						// Arguments
-						.arg(Self::get_target(&msg.target))
-						.arg(msg.message)
-						.arg(msg.invoker.name);
-					if let Some(uid) = &msg.invoker.uid {
-						cmd.arg(&BASE64_STANDARD.encode(&uid.0));
-					}
-					output = cmd.output();
-				} else {
-					// Shell
-					#[cfg(target_family = "unix")]
-					{
-						let mut cmd = Command::new("sh");
-						cmd
-							.arg("-c")
-							.arg(s)
-							// Program name
-							.arg("sh")
-							// Arguments
-							.arg(Self::get_target(&msg.target))
-							.arg(msg.message)
-							.arg(msg.invoker.name);
-						if let Some(uid) = &msg.invoker.uid {
-							cmd.arg(&BASE64_STANDARD.encode(&uid.0));
+			Reaction::Plain(s) => Some(Response::Text(s.render(msg, captures))),
+			Reaction::Random(r) => {
+				Some(Response::Text(r.pick().render(msg, captures)))
+			}
+			Reaction::Command(script) | Reaction::Shell(script) => {
+				let command = script.command.clone();
+				let input = ScriptInput::new(bot, con, msg, captures);
+				let mut child =
+					match self.build_command(&command, msg, &input).spawn() {
+						Ok(c) => c,
+						Err(error) => {
+							error!(%error, command = command.as_str(), "Failed to execute shell");
+							// Don't proceed
+							return Some(Response::Text("".into()));
+						}
+					};
+				let timeout = script.timeout.unwrap_or_else(|| {
+					Duration::from_secs(bot.settings.script_timeout)
+				});
+				let max_output =
+					script.max_output.unwrap_or(bot.settings.script_max_output);
+				let json_output = script.json_output;
+
+				let input = serde_json::to_vec(&input)
+					.expect("Failed to serialize script input");
+
+				Some(Response::Script(Box::pin(async move {
+					let mut stdin = child.stdin.take().unwrap();
+					let mut stdout = child.stdout.take().unwrap();
+					let write = async move {
+						// Scripts which do not read stdin may close it early
+						if let Err(error) = stdin.write_all(&input).await {
+							debug!(%error, "Failed to write script input");
+						}
+					};
+					let read = async {
+						// Read the whole output so the script does not block,
+						// but only keep max_output bytes.
+						let mut output = Vec::new();
+						let mut len = 0;
+						let mut buf = [0; 4096];
+						loop {
+							let n = stdout.read(&mut buf).await?;
+							if n == 0 {
+								break;
+							}
+							let keep = n.min(max_output - output.len());
+							output.extend_from_slice(&buf[..keep]);
+							len += n;
 						}
-						output = cmd.output();
+						Ok::<_, std::io::Error>((output, len))
+					};
+					let run = async {
+						let ((), output) = tokio::join!(write, read);
+						let (output, len) = output?;
+						let status = child.wait().await?;
+						Ok::<_, std::io::Error>((status, output, len))
+					};
+
+					let (status, mut output, len) =
+						match tokio::time::timeout(timeout, run).await {
+							Ok(Ok(r)) => r,
+							Ok(Err(error)) => {
+								error!(%error, command = command.as_str(), "Failed to execute shell");
+								// Don't proceed
+								return Some(Vec::new());
+							}
+							Err(_) => {
+								warn!(
+									command = command.as_str(),
+									?timeout,
+									"Killed script because it took too long"
+								);
+								if let Err(error) = child.kill().await {
+									error!(%error, command = command.as_str(), "Failed to kill script");
+								}
+								// Don't proceed
+								return Some(Vec::new());
+							}
+						};
+					if !status.success() {
+						// Skip and try next action
+						return None;
 					}
 
-					#[cfg(not(target_family = "unix"))]
-					{
-						// Windows is untested
-						let mut cmd = Command::new("cmd");
-						cmd
-							.arg("/C")
-							.arg(s)
-							// Arguments
-							.arg(Self::get_target(&msg.target))
-							.arg(msg.message)
-							.arg(msg.invoker.name);
-						if let Some(uid) = &msg.invoker.uid {
-							cmd.arg(&base64::encode(&uid.0));
+					if len > output.len() {
+						warn!(
+							command = command.as_str(),
+							len, max_output, "Truncated script output"
+						);
+						// Do not cut a character in half
+						if let Err(error) = std::str::from_utf8(&output) {
+							if error.error_len().is_none() {
+								output.truncate(error.valid_up_to());
+							}
 						}
-						output = cmd.output();
 					}
-				}
 
-				let output = match output {
-					Ok(o) => o,
-					Err(error) => {
-						error!(%error, command = s.as_str(), "Failed to execute shell");
-						// Don't proceed
-						return Some("".into());
+					// Try to parse result
+					let output = match String::from_utf8(output) {
+						Ok(r) => r,
+						Err(error) => {
+							error!(%error, command = command.as_str(), output = ?error.as_bytes(), "Failed to parse output");
+							// Don't proceed
+							return Some(Vec::new());
+						}
+					};
+					if !json_output {
+						return Some(Operation::from_text(output));
 					}
-				};
-				if !output.status.success() {
-					// Skip and try next action
-					return None;
+					match Operation::parse_list(&output) {
+						Ok(r) => Some(r),
+						Err(error) => {
+							error!(%error, command = command.as_str(), output = output.as_str(), "Failed to parse script operations");
+							// Don't proceed
+							Some(Vec::new())
+						}
+					}
+				})))
+			}
+			Reaction::Script(script) => {
+				let input = ScriptInput::new(bot, con, msg, captures);
+				Some(Response::Script(script.run(bot, input)))
+			}
+			Reaction::Wasm(plugin) => {
+				let input = ScriptInput::new(bot, con, msg, captures);
+				// Handle the operations like the output of a script
+				Some(Response::Script(plugin.run(bot, input)))
+			}
+			Reaction::Plugin { plugin, command } => bot.plugins[*plugin]
+				.run_command(bot, con, command, msg)
+				.map(Response::Text),
+		}
+	}
+}
+
+impl Operation {
+	/// Parse the JSON output of a script.
+	pub fn parse_list(output: &str) -> Result<Vec<Self>> {
+		let res: Vec<Self> = serde_json::from_str(output)?;
+		for o in &res {
+			o.validate()?;
+		}
+		Ok(res)
+	}
+
+	/// Reject fields which would be ignored.
+	fn validate(&self) -> Result<()> {
+		match self {
+			Operation::Send {
+				target,
+				client: Some(_),
+				..
+			} if *target != Some(OperationTarget::Client) => {
+				bail!("client can only be used with target client")
+			}
+			Operation::Later { operations, .. } => {
+				for o in operations {
+					o.validate()?;
 				}
+			}
+			_ => {}
+		}
+		Ok(())
+	}
 
-				// Try to parse result
-				let res = match std::str::from_utf8(&output.stdout) {
-					Ok(r) => r,
-					Err(error) => {
-						error!(%error, command = s.as_str(), output = ?output.stdout, "Failed to parse output");
-						// Don't proceed
-						return Some("".into());
-					}
-				};
+	/// The operations for the text output of a script.
+	pub fn from_text(output: String) -> Vec<Self> {
+		if output.is_empty() {
+			Vec::new()
+		} else {
+			vec![Operation::Send {
+				target: None,
+				client: None,
+				message: output,
+			}]
+		}
+	}
+}
+
+/// Unset all `SIMPLEBOT_` variables of the environment `vars` of the bot.
+///
+/// Otherwise scripts could see inherited values for variables which are not
+/// set for a message, like `SIMPLEBOT_INVOKER_UID`.
+fn remove_inherited_env(
+	cmd: &mut Command,
+	vars: impl IntoIterator<Item = (OsString, OsString)>,
+) {
+	for (name, _) in vars {
+		if name.to_string_lossy().starts_with("SIMPLEBOT_") {
+			cmd.env_remove(name);
+		}
+	}
+}
+
+impl ScriptInput {
+	pub fn new(
+		bot: &Bot,
+		con: &dyn BotConnection,
+		msg: &Message,
+		captures: &Captures,
+	) -> Self {
+		let channel = con
+			.client(msg.invoker.id)
+			.and_then(|c| con.channel(c.channel))
+			.map(|c| ScriptChannel {
+				id: c.id.0,
+				name: c.name,
+			});
+		let bot_name = con
+			.own_client()
+			.ok()
+			.and_then(|id| con.client(id))
+			.map(|c| c.name)
+			.unwrap_or_else(|| bot.settings.name.clone());
+		Self {
+			target: Reaction::get_target(&msg.target),
+			message: msg.message.into(),
+			invoker: ScriptInvoker {
+				id: msg.invoker.id.0,
+				name: msg.invoker.name.into(),
+				uid: msg.invoker.uid.map(|u| BASE64_STANDARD.encode(&u.0)),
+			},
+			channel,
+			captures: captures
+				.iter()
+				.map(|(k, v)| (k.clone(), v.clone()))
+				.collect(),
+			bot_name,
+		}
+	}
 
-				Some(res.to_string().into())
+	/// The environment variables for a script.
+	///
+	/// Captures are named `SIMPLEBOT_CAPTURE_<name>`, unset values are left
+	/// out.
+	pub fn env(&self) -> Vec<(String, String)> {
+		let mut res = vec![
+			("SIMPLEBOT_TARGET".into(), self.target.into()),
+			("SIMPLEBOT_MESSAGE".into(), self.message.clone()),
+			("SIMPLEBOT_INVOKER_ID".into(), self.invoker.id.to_string()),
+			("SIMPLEBOT_INVOKER_NAME".into(), self.invoker.name.clone()),
+			("SIMPLEBOT_BOT_NAME".into(), self.bot_name.clone()),
+		];
+		if let Some(uid) = &self.invoker.uid {
+			res.push(("SIMPLEBOT_INVOKER_UID".into(), uid.clone()));
+		}
+		if let Some(channel) = &self.channel {
+			res.push(("SIMPLEBOT_CHANNEL_ID".into(), channel.id.to_string()));
+			res.push(("SIMPLEBOT_CHANNEL_NAME".into(), channel.name.clone()));
+		}
+		for (name, value) in &self.captures {
+			res.push((format!("SIMPLEBOT_CAPTURE_{}", name), value.clone()));
+		}
+		res
+	}
+}
+
+impl EventActions {
+	/// Get the actions for an event.
+	///
+	/// # Panics
+	/// Panics for [`Trigger::Message`] and [`Trigger::Timer`], these actions
+	/// are not events.
+	pub fn get(&self, trigger: Trigger) -> &ActionList {
+		match trigger {
+			Trigger::Join => &self.join,
+			Trigger::Leave => &self.leave,
+			Trigger::ChannelEnter => &self.channel_enter,
+			Trigger::ChannelLeave => &self.channel_leave,
+			Trigger::Message | Trigger::Timer => {
+				panic!("{:?} is no event", trigger)
 			}
-			Reaction::Function(f) => f(bot, con, msg),
 		}
 	}
 }
 
 impl ActionList {
+	/// Check if an action with a reaction matches the message.
+	///
+	/// Permissions and cooldowns are not checked.
+	pub fn matches(&self, con: &dyn BotConnection, msg: &Message) -> bool {
+		self.0.iter().any(|a| {
+			a.matchers.iter().all(|m| m.matches(con, msg))
+				&& a.reaction.is_some()
+		})
+	}
+
+	/// Search the first matching action, starting with the action at index
+	/// `start`.
 	pub fn handle<'a>(
 		&'a self,
 		bot: &Bot,
-		con: &mut Connection,
+		con: &mut dyn BotConnection,
 		msg: &'a Message,
-	) -> Option<Cow<'a, str>> {
-		'actions: for a in &self.0 {
+		start: usize,
+	) -> Handled<'a> {
+		'actions: for (i, a) in self.0.iter().enumerate().skip(start) {
 			for m in &a.matchers {
-				if !m.matches(msg) {
+				if !m.matches(con, msg) {
 					continue 'actions;
 				}
 			}
 
-			if let Some(a) = &a.reaction {
-				if let Some(res) = a.execute(bot, con, msg) {
-					if res == "" {
-						return None;
-					} else {
-						return Some(res);
+			if let Some(name) = &a.name {
+				let allowed = if let Some(Reaction::Plugin { .. }) = a.reaction
+				{
+					permission::is_allowed(bot, con, name, &msg.invoker)
+				} else {
+					permission::is_action_allowed(bot, con, name, &msg.invoker)
+				};
+				if !allowed {
+					info!(message = ?msg, action = name.as_str(), "Action not allowed");
+					return Handled::NotAllowed;
+				}
+			}
+
+			if let Some(c) = &a.cooldown {
+				if c.is_cooling_down(&bot.cooldowns, msg) {
+					debug!(message = ?msg, action = i, "Action is cooling down");
+					if c.next {
+						continue;
 					}
+					return Handled::Nothing;
+				}
+			}
+
+			if let Some(r) = &a.reaction {
+				let captures = a.captures(msg);
+				match r.execute(bot, con, msg, &captures) {
+					Some(Response::Text(res)) => {
+						a.start_cooldown(bot, msg);
+						if res.is_empty() {
+							return Handled::Nothing;
+						} else {
+							return Handled::Response(res);
+						}
+					}
+					// The cooldown starts when the script handled the message
+					Some(Response::Script(f)) => return Handled::Pending(i, f),
+					None => {}
 				}
 			} else {
-				return None;
+				a.start_cooldown(bot, msg);
+				return Handled::Nothing;
 			}
 		}
-		None
+		Handled::Nothing
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::ffi::OsStr;
+	use std::fs;
+
+	use tsclientlib::{ChannelId, ClientId, ServerGroupId, UidBuf};
+
+	use super::*;
+	use crate::connection::{ChannelInfo, ClientInfo};
+	use crate::test_util::{
+		test_client, test_message as message, FakeConnection, TestDir,
+	};
+
+	fn contains(s: &str) -> Action {
+		ActionDefinition {
+			matcher: MatcherDefinition {
+				contains: Some(s.into()),
+				..Default::default()
+			},
+			..Default::default()
+		}
+		.to_action()
+		.unwrap()
+	}
+
+	fn handle(list: &ActionList, msg: &Message) -> Option<String> {
+		handle_in(&Bot::new(), list, msg)
+	}
+
+	fn handle_in(
+		bot: &Bot,
+		list: &ActionList,
+		msg: &Message,
+	) -> Option<String> {
+		let mut con = FakeConnection::new();
+		match list.handle(bot, &mut con, msg, 0) {
+			Handled::Nothing => None,
+			Handled::Response(r) => Some(r.into_owned()),
+			Handled::NotAllowed => Some(bot.settings.not_allowed.clone()),
+			Handled::Pending(..) => panic!("Unexpected script"),
+		}
+	}
+
+	#[test]
+	fn script_input() {
+		let bot = Bot::new();
+		let mut con = FakeConnection::new();
+		con.clients.push(test_client());
+		let msg = message(MessageTarget::Channel, "roll 2d6");
+		let regex = Regex::new(r"roll (?P<count>\d+)d6").unwrap();
+		let mut captures = Captures::new();
+		template::captures(&regex, msg.message, &mut captures);
+
+		let input = ScriptInput::new(&bot, &con, &msg, &captures);
+		assert_eq!(
+			serde_json::to_value(&input).unwrap(),
+			serde_json::json!({
+				"target": "channel",
+				"message": "roll 2d6",
+				"invoker": { "id": 2, "name": "Alice", "uid": null },
+				"channel": { "id": 1, "name": "Default Channel" },
+				"captures": { "0": "roll 2d6", "1": "2", "count": "2" },
+				"bot_name": "SimpleBot",
+			})
+		);
+		let env = input.env();
+		assert!(env.contains(&("SIMPLEBOT_CAPTURE_count".into(), "2".into())));
+		assert!(env.contains(&("SIMPLEBOT_CHANNEL_ID".into(), "1".into())));
+		assert!(!env.iter().any(|(k, _)| k == "SIMPLEBOT_INVOKER_UID"));
+
+		// Variables of the bot are not inherited
+		let mut cmd = Command::new("true");
+		remove_inherited_env(
+			&mut cmd,
+			[
+				("SIMPLEBOT_INVOKER_UID".into(), "x".into()),
+				("PATH".into(), "/bin".into()),
+			],
+		);
+		let envs = cmd.as_std().get_envs().collect::<Vec<_>>();
+		assert_eq!(envs, [(OsStr::new("SIMPLEBOT_INVOKER_UID"), None)]);
+	}
+
+	#[test]
+	fn to_action_errors() {
+		let defs = [
+			ActionDefinition {
+				matcher: MatcherDefinition {
+					contains: Some("a".into()),
+					regex: Some("b".into()),
+					..Default::default()
+				},
+				..Default::default()
+			},
+			ActionDefinition {
+				matcher: MatcherDefinition {
+					regex: Some("(".into()),
+					..Default::default()
+				},
+				..Default::default()
+			},
+			ActionDefinition {
+				matcher: MatcherDefinition {
+					chat: Some("everywhere".into()),
+					..Default::default()
+				},
+				..Default::default()
+			},
+			ActionDefinition {
+				response: Some("a".into()),
+				shell: Some("b".into()),
+				..Default::default()
+			},
+			ActionDefinition {
+				response: Some("a".into()),
+				timeout: Some(1),
+				..Default::default()
+			},
+			ActionDefinition {
+				response: Some("a".into()),
+				json_output: Some(true),
+				..Default::default()
+			},
+			ActionDefinition {
+				matcher: MatcherDefinition {
+					regex: Some("(?P<name>a)".into()),
+					..Default::default()
+				},
+				response: Some("{other}".into()),
+				..Default::default()
+			},
+		];
+		for d in &defs {
+			assert!(d.to_action().is_err(), "{:?} should fail", d);
+		}
+	}
+
+	#[test]
+	fn to_action_matchers() {
+		let a = ActionDefinition {
+			matcher: MatcherDefinition {
+				regex: Some("^a".into()),
+				chat: Some("poke".into()),
+				..Default::default()
+			},
+			response: Some("b".into()),
+			..Default::default()
+		}
+		.to_action()
+		.unwrap();
+		assert_eq!(a.matchers.len(), 2);
+		assert!(matches!(a.matchers[1], Matcher::Mode(None)));
+		assert!(matches!(a.reaction, Some(Reaction::Plain(_))));
+	}
+
+	#[test]
+	fn contains_matches_words() {
+		let con = FakeConnection::new();
+		let a = contains("question");
+		let m = &a.matchers[0];
+		assert!(m.matches(&con, &message(MessageTarget::Channel, "a question")));
+		assert!(m.matches(&con, &message(MessageTarget::Channel, "question?")));
+		assert!(
+			!m.matches(&con, &message(MessageTarget::Channel, "questionable"))
+		);
+		assert!(!m.matches(&con, &message(MessageTarget::Channel, "Question")));
+
+		// No word boundaries for non-alphabetic characters
+		let a = contains("?");
+		assert!(
+			a.matchers[0].matches(&con, &message(MessageTarget::Server, "a?b"))
+		);
+	}
+
+	#[test]
+	fn contains_match_modes() {
+		let con = FakeConnection::new();
+		let matches = |mode: &str, ignore_case, msg| {
+			let a = ActionDefinition {
+				matcher: MatcherDefinition {
+					contains: Some("Hi".into()),
+					match_mode: Some(mode.into()),
+					ignore_case: Some(ignore_case),
+					..Default::default()
+				},
+				..Default::default()
+			}
+			.to_action()
+			.unwrap();
+			a.matchers[0].matches(&con, &message(MessageTarget::Channel, msg))
+		};
+		assert!(matches("word", false, "oh Hi"));
+		assert!(!matches("word", false, "oh hi"));
+		assert!(matches("word", true, "oh hi"));
+		assert!(matches("substring", false, "oHio"));
+		assert!(matches("exact", true, "hi"));
+		assert!(!matches("exact", true, "hi there"));
+		assert!(matches("prefix", false, "Hit"));
+		assert!(!matches("prefix", false, "a Hi"));
+
+		let def = ActionDefinition {
+			matcher: MatcherDefinition {
+				regex: Some("a".into()),
+				match_mode: Some("exact".into()),
+				..Default::default()
+			},
+			..Default::default()
+		};
+		assert!(def.to_action().is_err());
+	}
+
+	#[test]
+	fn mode_matches() {
+		let con = FakeConnection::new();
+		let client = MessageTarget::Client(ClientId(1));
+		let poke = MessageTarget::Poke(ClientId(1));
+		let m = Matcher::Mode(Some(TextMessageTargetMode::Client));
+		assert!(m.matches(&con, &message(client, "")));
+		assert!(!m.matches(&con, &message(poke, "")));
+		assert!(!m.matches(&con, &message(MessageTarget::Server, "")));
+		let m = Matcher::Mode(None);
+		assert!(m.matches(&con, &message(poke, "")));
+		assert!(!m.matches(&con, &message(MessageTarget::Channel, "")));
+	}
+
+	#[test]
+	fn invoker_matches() {
+		let mut con = FakeConnection::new();
+		con.clients.push(ClientInfo {
+			server_groups: vec![ServerGroupId(6)],
+			..test_client()
+		});
+		let uid = UidBuf(b"alice".to_vec());
+		let mut msg = message(MessageTarget::Channel, "");
+		msg.invoker.uid = Some(&uid);
+
+		let a = ActionDefinition {
+			matcher: MatcherDefinition {
+				from_uid: Some(vec![BASE64_STANDARD.encode(b"alice")]),
+				from_name: Some("^Al".into()),
+				from_server_group: Some(vec![5, 6]),
+				..Default::default()
+			},
+			..Default::default()
+		}
+		.to_action()
+		.unwrap();
+		assert_eq!(a.matchers.len(), 3);
+		assert!(a.matchers.iter().all(|m| m.matches(&con, &msg)));
+
+		msg.invoker.uid = None;
+		msg.invoker.name = "Bob";
+		con.clients[1].server_groups.clear();
+		assert!(a.matchers.iter().all(|m| !m.matches(&con, &msg)));
+
+		let def = ActionDefinition {
+			matcher: MatcherDefinition {
+				from_uid: Some(vec!["not base64!".into()]),
+				..Default::default()
+			},
+			..Default::default()
+		};
+		assert!(def.to_action().is_err());
+	}
+
+	#[test]
+	fn matcher_tree() {
+		let con = FakeConnection::new();
+		let def: ActionDefinition = toml::from_str(
+			r#"
+			contains = "hi"
+			[not]
+			contains = "bye"
+			[[any_of]]
+			chat = "server"
+			[[any_of]]
+			chat = "channel"
+			contains = "there"
+			"#,
+		)
+		.unwrap();
+		let a = def.to_action().unwrap();
+		let matches = |target, msg| {
+			a.matchers
+				.iter()
+				.all(|m| m.matches(&con, &message(target, msg)))
+		};
+		assert!(matches(MessageTarget::Server, "hi"));
+		assert!(!matches(MessageTarget::Server, "hi and bye"));
+		assert!(matches(MessageTarget::Channel, "hi there"));
+		assert!(!matches(MessageTarget::Channel, "hi"));
+		assert!(!matches(MessageTarget::Poke(ClientId(2)), "hi there"));
+
+		for s in [
+			"[[all_of]]\nchat = \"nowhere\"",
+			"not = {}",
+			"any_of = []",
+			"all_of = []",
+			"any_of = [{ contains = \"a\" }, {}]",
+		] {
+			let def: ActionDefinition = toml::from_str(s).unwrap();
+			assert!(def.to_action().is_err(), "{} should fail", s);
+		}
+		// Unknown fields are still rejected
+		assert!(toml::from_str::<ActionDefinition>("contain = \"a\"").is_err());
+		assert!(toml::from_str::<ActionDefinition>("not = { a = 1 }").is_err());
+	}
+
+	#[test]
+	fn random_responses() {
+		let list = ActionList(vec![
+			toml::from_str::<ActionDefinition>(
+				"response = { a = 1, b = 0 }\ncontains = \"w\"",
+			)
+			.unwrap()
+			.to_action()
+			.unwrap(),
+			toml::from_str::<ActionDefinition>(
+				"response = [\"a\", \"b\"]\nno_repeat = 1",
+			)
+			.unwrap()
+			.to_action()
+			.unwrap(),
+		]);
+		let msg = message(MessageTarget::Channel, "w");
+		for _ in 0..10 {
+			assert_eq!(handle(&list, &msg).as_deref(), Some("a"));
+		}
+		// Responses alternate
+		let msg = message(MessageTarget::Channel, "x");
+		let first = handle(&list, &msg).unwrap();
+		for _ in 0..10 {
+			assert_ne!(handle(&list, &msg).unwrap(), first);
+			assert_eq!(handle(&list, &msg).unwrap(), first);
+		}
+
+		let defs = ["response = []", "response = { a = 0 }", "no_repeat = 1"];
+		for d in defs {
+			let def = toml::from_str::<ActionDefinition>(d).unwrap();
+			assert!(def.to_action().is_err(), "{} should fail", d);
+		}
+	}
+
+	#[test]
+	fn weighted_responses() {
+		let mut def = ResponseDefinition::Weighted(
+			[("a".to_string(), u32::MAX), ("b".to_string(), u32::MAX)].into(),
+		);
+		// The weight of existing responses is kept
+		def.push("a".into());
+		def.push("c".into());
+		match &def {
+			ResponseDefinition::Weighted(w) => {
+				assert_eq!(w["a"], u32::MAX);
+				assert_eq!(w["c"], 1);
+			}
+			_ => panic!("Expected weighted responses"),
+		}
+
+		// Weights can add up to more than u32::MAX
+		match def.to_reaction(None).unwrap() {
+			Reaction::Random(r) => {
+				for _ in 0..100 {
+					assert_ne!(r.pick().as_str(), "c");
+				}
+			}
+			_ => panic!("Expected a random reaction"),
+		}
+	}
+
+	#[test]
+	fn cooldown() {
+		let cooldown = |per: &str, behavior: &str| {
+			ActionDefinition {
+				response: Some("a".into()),
+				cooldown: Some("1h".into()),
+				cooldown_per: Some(per.into()),
+				cooldown_behavior: Some(behavior.into()),
+				..Default::default()
+			}
+			.to_action()
+			.unwrap()
+		};
+		let fallback = ActionDefinition {
+			response: Some("b".into()),
+			..Default::default()
+		}
+		.to_action()
+		.unwrap();
+		let list = ActionList(vec![cooldown("user", "next"), fallback]);
+
+		let bot = Bot::new();
+		let mut msg = message(MessageTarget::Channel, "");
+		assert_eq!(handle_in(&bot, &list, &msg).as_deref(), Some("a"));
+		assert_eq!(handle_in(&bot, &list, &msg).as_deref(), Some("b"));
+		msg.invoker.id = ClientId(3);
+		assert_eq!(handle_in(&bot, &list, &msg).as_deref(), Some("a"));
+
+		let list = ActionList(vec![cooldown("target", "silent")]);
+		assert_eq!(handle_in(&bot, &list, &msg).as_deref(), Some("a"));
+		assert_eq!(handle_in(&bot, &list, &msg), None);
+		msg.target = MessageTarget::Server;
+		assert_eq!(handle_in(&bot, &list, &msg).as_deref(), Some("a"));
+
+		let def = ActionDefinition {
+			cooldown_per: Some("user".into()),
+			..Default::default()
+		};
+		assert!(def.to_action().is_err());
+	}
+
+	#[test]
+	fn cooldown_reload() {
+		let dir = TestDir::new("cooldown_reload");
+		let mut bot = Bot::new();
+		bot.base_dir = dir.to_path_buf();
+		bot.extra_actions = toml::from_str(
+			"[[on_message]]\ncontains = \"a\"\nresponse = \"b\"\ncooldown = \
+			 \"1h\"\n",
+		)
+		.unwrap();
+		crate::load_settings(&mut bot).unwrap();
+		let msg = message(MessageTarget::Channel, "a");
+		assert_eq!(handle_in(&bot, &bot.actions, &msg).as_deref(), Some("b"));
+		// The cooldown is kept when reloading
+		crate::load_settings(&mut bot).unwrap();
+		assert_eq!(handle_in(&bot, &bot.actions, &msg), None);
+	}
+
+	#[tokio::test]
+	async fn cooldown_fall_through() {
+		let dir = TestDir::new("cooldown_fall_through");
+		fs::write(
+			dir.join("script.rhai"),
+			"if message == \"go\" { reply(\"a\"); } else { false }",
+		)
+		.unwrap();
+		let def: ActionDefinition = toml::from_str(
+			"script = \"script.rhai\"\ncooldown = \"1h\"\ncooldown_behavior = \
+			 \"next\"",
+		)
+		.unwrap();
+		let mut bot = Bot::new();
+		bot.settings.rate_limit = 100;
+		bot.actions = ActionList(vec![
+			def.to_action_in(&dir).unwrap(),
+			ActionDefinition {
+				response: Some("b".into()),
+				..Default::default()
+			}
+			.to_action()
+			.unwrap(),
+		]);
+
+		// The cooldown only starts if the action handled the message
+		let mut con = FakeConnection::new();
+		let go = message(MessageTarget::Channel, "go");
+		let other = message(MessageTarget::Channel, "other");
+		for msg in [&other, &go, &go] {
+			bot.process_message(&mut con, msg).await;
+		}
+		let sent = con.sent.iter().map(|(_, m)| m.as_str()).collect::<Vec<_>>();
+		assert_eq!(sent, ["b", "a", "b"]);
+	}
+
+	#[test]
+	fn handle_first_match() {
+		let list = ActionList(vec![
+			ActionDefinition {
+				matcher: MatcherDefinition {
+					contains: Some("a".into()),
+					chat: Some("server".into()),
+					..Default::default()
+				},
+				response: Some("server a".into()),
+				..Default::default()
+			}
+			.to_action()
+			.unwrap(),
+			ActionDefinition {
+				matcher: MatcherDefinition {
+					regex: Some(r"^(\w) (?P<second>\w)".into()),
+					..Default::default()
+				},
+				response: Some("{1}-{second} from {invoker.name}".into()),
+				..Default::default()
+			}
+			.to_action()
+			.unwrap(),
+		]);
+
+		let msg = message(MessageTarget::Server, "a b");
+		assert_eq!(handle(&list, &msg).as_deref(), Some("server a"));
+		let msg = message(MessageTarget::Channel, "a b");
+		assert_eq!(handle(&list, &msg).as_deref(), Some("a-b from Alice"));
+		let msg = message(MessageTarget::Channel, "nothing");
+		assert_eq!(handle(&list, &msg), None);
+	}
+
+	#[test]
+	fn handle_without_reaction() {
+		// An action without reaction stops the search
+		let list = ActionList(vec![contains("a"), {
+			let mut a = contains("a");
+			a.reaction =
+				Some(Reaction::Plain(Template::parse("b", None).unwrap()));
+			a
+		}]);
+		let msg = message(MessageTarget::Channel, "a");
+		assert_eq!(handle(&list, &msg), None);
+	}
+
+	#[test]
+	fn operations() {
+		let ops = Operation::parse_list(
+			r#"[
+				{ "op": "send", "target": "server", "message": "Hi" },
+				{ "op": "poke", "client": 3, "message": "Wake up" },
+				{ "op": "move", "channel": "Lobby/AFK" },
+				{ "op": "kick", "client": 3, "from": "server" },
+				{ "op": "continue" }
+			]"#,
+		)
+		.unwrap();
+		assert_eq!(
+			ops,
+			vec![
+				Operation::Send {
+					target: Some(OperationTarget::Server),
+					client: None,
+					message: "Hi".into(),
+				},
+				Operation::Poke {
+					client: Some(3),
+					message: "Wake up".into(),
+				},
+				Operation::Move {
+					channel: ChannelDefinition::Name("Lobby/AFK".into()),
+				},
+				Operation::Kick {
+					client: 3,
+					from: KickFrom::Server,
+					reason: String::new(),
+				},
+				Operation::Continue,
+			]
+		);
+
+		for invalid in [
+			r#"[{ "op": "ban", "client": 3 }]"#,
+			r#"[{ "op": "send", "message": "Hi", "color": "red" }]"#,
+			r#"[{ "op": "send", "target": "everyone", "message": "Hi" }]"#,
+			r#"{ "op": "stop" }"#,
+			r#"[{ "op": "send", "client": 3, "message": "Hi" }]"#,
+			r#"[{ "op": "send", "target": "server", "client": 3, "message": "Hi" }]"#,
+		] {
+			assert!(Operation::parse_list(invalid).is_err(), "{}", invalid);
+		}
+
+		assert_eq!(Operation::from_text(String::new()), vec![]);
+		assert_eq!(
+			Operation::from_text("Hi".into()),
+			vec![Operation::Send {
+				target: None,
+				client: None,
+				message: "Hi".into(),
+			}]
+		);
+	}
+
+	#[test]
+	fn perform_operations() {
+		let mut bot = Bot::new();
+		bot.settings.rate_limit = 100;
+		let mut con = FakeConnection::new();
+		con.clients.push(ClientInfo {
+			id: ClientId(3),
+			name: "Bob".into(),
+			..test_client()
+		});
+		con.channels.push(ChannelInfo {
+			id: ChannelId(2),
+			name: "AFK".into(),
+			parent: ChannelId(0),
+		});
+		let msg = message(MessageTarget::Channel, "");
+		let perform = |bot: &mut Bot, con: &mut FakeConnection, ops: &str| {
+			let ops = Operation::parse_list(ops).unwrap();
+			crate::perform_operations(bot, con, Trigger::Message, &msg, 0, ops)
+		};
+
+		let long = "a".repeat(150);
+		let ops = format!(
+			r#"[
+				{{ "op": "send", "message": "Hi" }},
+				{{ "op": "send", "target": "client", "message": "Private" }},
+				{{ "op": "send", "target": "client", "client": 3, "message": "Bob" }},
+				{{ "op": "poke", "client": 3, "message": "{}" }},
+				{{ "op": "poke", "client": 4, "message": "Nobody" }},
+				{{ "op": "move", "channel": "AFK" }},
+				{{ "op": "kick", "client": 3, "from": "server" }},
+				{{ "op": "continue" }}
+			]"#,
+			long
+		);
+		assert!(perform(&mut bot, &mut con, &ops));
+		assert_eq!(
+			con.sent,
+			[
+				(MessageTarget::Channel, "Hi".into()),
+				(MessageTarget::Client(ClientId(2)), "Private".into()),
+				(MessageTarget::Client(ClientId(3)), "Bob".into()),
+				// Pokes are split at 100 characters
+				(MessageTarget::Poke(ClientId(3)), long[..100].into()),
+				(MessageTarget::Poke(ClientId(3)), long[100..].into()),
+			]
+		);
+		assert_eq!(con.clients[0].channel, ChannelId(2));
+		assert_eq!(con.kicked, [(ClientId(3), true)]);
+
+		// Operations after stop are ignored
+		con.sent.clear();
+		let ops = r#"[
+			{ "op": "stop" },
+			{ "op": "send", "message": "Hi" },
+			{ "op": "continue" }
+		]"#;
+		assert!(!perform(&mut bot, &mut con, ops));
+		assert!(con.sent.is_empty());
+		assert!(!perform(&mut bot, &mut con, "[]"));
 	}
 }
@@ -5,76 +5,177 @@ use std::path::Path;
 
 use regex::Regex;
 use tracing::{debug, error, info};
-use tsclientlib::{Connection, DisconnectOptions};
 
 use crate::action::*;
+use crate::connection::BotConnection;
+use crate::plugin::{Command, Plugin};
 use crate::{ActionFile, Bot, Message};
 
-/// Add builtin functions to the end of the action list.
-pub fn init(bot: &mut Bot) {
-	let p = regex::escape(&bot.settings.prefix);
-
-	let help_regex = Regex::new(&format!("^{}help", p)).unwrap();
-	add_fun(bot, help_regex, |b, _, _| help(b));
-
-	let copy_regex = Regex::new(&format!("^{}copy", p)).unwrap();
-	add_fun(bot, copy_regex, |_, _, _| copyright());
+/// The builtin commands like `help`, `add` and `list`.
+#[derive(Debug)]
+pub struct Builtins {
+	add_regex: Regex,
+	del_regex: Regex,
+	/// A cached list of actions, split into pages.
+	list: Vec<String>,
+}
 
-	let list_regex = Regex::new(&format!("^{}list", p)).unwrap();
-	add_fun(bot, list_regex, move |b, _, m| list(b, m));
+impl Default for Builtins {
+	fn default() -> Self {
+		Self {
+			add_regex: add_regex(""),
+			del_regex: del_regex(""),
+			list: vec![String::new()],
+		}
+	}
+}
 
-	let add_regex = Regex::new(&format!("^{}add", p)).unwrap();
-	let long_add_regex =
-		Regex::new(&format!("^{}add (?P<response>.*) on (?P<trigger>.*)$", p))
-			.unwrap();
-	add_fun(bot, add_regex, move |b, _, m| add(b, &long_add_regex, m));
+impl Plugin for Builtins {
+	fn name(&self) -> &str { "builtins" }
+
+	fn commands(&self, bot: &Bot) -> Vec<Command> {
+		let p = regex::escape(&bot.settings.prefix);
+		let command = |name: &str, regex: &str| Command {
+			name: name.into(),
+			regex: Regex::new(&format!("^{}{}", p, regex)).unwrap(),
+			restricted: true,
+		};
+		vec![
+			command("help", "help"),
+			// Not restricted by permissions, the copyright notice has to be
+			// available
+			Command {
+				restricted: false,
+				..command("copy", "copy")
+			},
+			command("list", "list"),
+			command("add", "add"),
+			command("del", "del"),
+			command("reload", "reload$"),
+			command("quit", "quit$"),
+		]
+	}
 
-	let del_regex = Regex::new(&format!("^{}del", p)).unwrap();
-	let long_del_regex =
-		Regex::new(&format!("^{}del (?P<trigger>.*)$", p)).unwrap();
-	add_fun(bot, del_regex, move |b, _, m| del(b, &long_del_regex, m));
+	fn run_command<'a>(
+		&self,
+		bot: &Bot,
+		con: &mut dyn BotConnection,
+		command: &str,
+		msg: &'a Message,
+	) -> Option<Cow<'a, str>> {
+		match command {
+			"help" => help(bot),
+			"copy" => copyright(),
+			"list" => list(bot, &self.list, msg),
+			"add" => add(bot, con, &self.add_regex, msg),
+			"del" => del(bot, &self.del_regex, msg),
+			"reload" => {
+				reload(bot);
+				Some("".into())
+			}
+			"quit" => quit(bot, con, msg),
+			_ => None,
+		}
+	}
 
-	let reload_regex = Regex::new(&format!("^{}reload$", p)).unwrap();
-	add_fun(bot, reload_regex, move |b, _, _| {
-		reload(b);
-		Some("".into())
-	});
+	fn on_reload(&mut self, bot: &Bot) {
+		let p = regex::escape(&bot.settings.prefix);
+		self.add_regex = add_regex(&p);
+		self.del_regex = del_regex(&p);
+		self.list = list_pages(&bot.actions);
+	}
+}
 
-	let quit_regex = Regex::new(&format!("^{}quit$", p)).unwrap();
-	add_fun(bot, quit_regex, quit);
+/// The regex for the options of `add`, `p` is the escaped prefix.
+fn add_regex(p: &str) -> Regex {
+	Regex::new(&format!(
+		"^{}add (?P<options>(?:--(?:\
+		 regex|ignore-case|command|shell|append|chat \\S+|match \\S+) \
+		 )*)(?P<response>.*) on (?P<trigger>.*)$",
+		p
+	))
+	.unwrap()
 }
 
-fn add_fun<
-	F: for<'a> Fn(&Bot, &mut Connection, &'a Message) -> Option<Cow<'a, str>>
-		+ Send
-		+ Sync
-		+ 'static,
->(
-	bot: &mut Bot,
-	r: Regex,
-	f: F,
-) {
-	bot.actions.0.push(Action {
-		matchers: vec![Matcher::Regex(r)],
-		reaction: Some(Reaction::Function(Box::new(f))),
-	});
+/// The regex for the trigger of `del`, `p` is the escaped prefix.
+fn del_regex(p: &str) -> Regex {
+	Regex::new(&format!("^{}del (?P<trigger>.*)$", p)).unwrap()
 }
 
-fn add<'a>(bot: &Bot, r: &Regex, msg: &'a Message) -> Option<Cow<'a, str>> {
+/// Add a new action to the dynamic actions.
+///
+/// Supported options are `--regex` to use the trigger as regex,
+/// `--match <mode>` to set how the trigger matches, `--ignore-case`,
+/// `--chat <mode>` to only react in one chat mode and `--command` or `--shell`
+/// to execute the response. Commands need the `add_command` permission, which
+/// has to be explicitly configured.
+///
+/// With `--append`, the response is added as an alternative to an existing
+/// action with the same trigger and a random response is picked.
+fn add<'a>(
+	bot: &Bot,
+	con: &mut dyn BotConnection,
+	r: &Regex,
+	msg: &'a Message,
+) -> Option<Cow<'a, str>> {
 	let caps = match r.captures(msg.message) {
 		Some(r) => r,
 		None => {
 			return Some(
 				format!(
-					"Usage: {}add <response> on <trigger>",
+					"Usage: {}add [--regex|--match <mode>] [--ignore-case] \
+					 [--chat <mode>] [--command|--shell|--append] <response> \
+					 on <trigger>",
 					crate::escape_bb(&bot.settings.prefix)
 				)
 				.into(),
 			);
 		}
 	};
-	let response = caps.name("response").unwrap();
-	let trigger = caps.name("trigger").unwrap();
+	let response = caps.name("response").unwrap().as_str();
+	let trigger = caps.name("trigger").unwrap().as_str();
+	if response.starts_with("--") {
+		let option = response.split(' ').next().unwrap();
+		return Some(
+			format!("Unknown option {}", crate::escape_bb(option)).into(),
+		);
+	}
+
+	let mut def = ActionDefinition::default();
+	let mut append = false;
+	let mut options = caps.name("options").unwrap().as_str().split(' ');
+	while let Some(o) = options.next() {
+		match o {
+			"--regex" => def.matcher.regex = Some(trigger.into()),
+			"--ignore-case" => def.matcher.ignore_case = Some(true),
+			"--match" => {
+				def.matcher.match_mode = options.next().map(|s| s.into())
+			}
+			"--chat" => def.matcher.chat = options.next().map(|s| s.into()),
+			"--command" => def.command = Some(response.into()),
+			"--shell" => def.shell = Some(response.into()),
+			"--append" => append = true,
+			_ => {}
+		}
+	}
+	if def.matcher.regex.is_none() {
+		def.matcher.contains = Some(trigger.into());
+	}
+	if def.command.is_some() || def.shell.is_some() {
+		// Executing programs is only allowed if it is explicitly configured
+		let allowed = bot
+			.settings
+			.permissions
+			.get("add_command")
+			.map(|p| p.allows(con, &msg.invoker))
+			.unwrap_or(false);
+		if !allowed {
+			info!(message = ?msg, "Adding command not allowed");
+			return Some(bot.settings.not_allowed.clone().into());
+		}
+	} else {
+		def.response = Some(response.into());
+	}
 
 	// Load
 	let path = Path::new(&bot.settings.dynamic_actions);
@@ -97,15 +198,35 @@ fn add<'a>(bot: &Bot, r: &Regex, msg: &'a Message) -> Option<Cow<'a, str>> {
 		}
 	};
 
-	dynamic.on_message.push(ActionDefinition {
-		contains: Some(trigger.as_str().into()),
-		regex: None,
-		chat: None,
+	let changed = if append {
+		if def.response.is_none() {
+			return Some("Only responses can be appended".into());
+		}
+		let existing = dynamic.on_message.iter_mut().find(|a| {
+			a.matcher.contains == def.matcher.contains
+				&& a.matcher.regex == def.matcher.regex
+				&& a.response.is_some()
+		});
+		match existing {
+			Some(a) => {
+				a.response.as_mut().unwrap().push(response.into());
+				a
+			}
+			None => return Some("No action with this trigger found".into()),
+		}
+	} else {
+		dynamic.on_message.push(def);
+		dynamic.on_message.last().unwrap()
+	};
 
-		response: Some(response.as_str().into()),
-		command: None,
-		shell: None,
-	});
+	// Check the action before saving it, otherwise the dynamic actions fail
+	// to load.
+	if let Err(error) = changed.to_action() {
+		return Some(
+			format!("Invalid action: {}", crate::escape_bb(&error.to_string()))
+				.into(),
+		);
+	}
 
 	// Save
 	if let Err(error) = fs::write(&path, toml::to_string(&dynamic).unwrap()) {
@@ -156,7 +277,8 @@ fn del<'a>(bot: &Bot, r: &Regex, msg: &'a Message) -> Option<Cow<'a, str>> {
 
 	let mut count = 0;
 	dynamic.on_message.retain(|a| {
-		let r = a.contains.as_ref().map(|c| c != trigger).unwrap_or(true);
+		let r = a.matcher.contains.as_deref() != Some(trigger)
+			&& a.matcher.regex.as_deref() != Some(trigger);
 		if !r {
 			count += 1;
 		}
@@ -180,14 +302,15 @@ fn del<'a>(bot: &Bot, r: &Regex, msg: &'a Message) -> Option<Cow<'a, str>> {
 fn reload(bot: &Bot) { bot.should_reload.set(true); }
 
 fn quit<'a>(
-	_: &Bot,
-	con: &mut Connection,
+	bot: &Bot,
+	con: &mut dyn BotConnection,
 	message: &'a Message,
 ) -> Option<Cow<'a, str>> {
 	info!(?message, "Leaving on request");
+	bot.should_quit.set(true);
 	// We get no disconnect message here
 	// Ignore errors on disconnect
-	let _ = con.disconnect(DisconnectOptions::new());
+	let _ = con.disconnect();
 	Some("".into())
 }
 
@@ -197,6 +320,8 @@ fn help<'a>(bot: &Bot) -> Option<Cow<'a, str>> {
 		format!(
 			"This is a [URL=https://github.com/ReSpeak/SimpleBot]SimpleBot[/URL].\n\
 			Use [i]{prefix}add <reaction> on <trigger>[/i] to add new actions\n\
+			(options: [i]--regex[/i], [i]--match <mode>[/i], [i]--ignore-case[/i],\n\
+			[i]--chat <mode>[/i], [i]--command[/i], [i]--shell[/i], [i]--append[/i])\n\
 			or [i]{prefix}del <trigger>[/i] to remove them.\n\
 			[i]{prefix}list[/i] lists all commands and actions.\n\
 			[i]{prefix}quit[/i] disconnects the bot.",
@@ -218,7 +343,11 @@ fn copyright() -> Option<Cow<'static, str>> {
 	)
 }
 
-fn list<'a>(bot: &Bot, msg: &Message) -> Option<Cow<'a, str>> {
+fn list<'a>(
+	bot: &Bot,
+	pages: &[String],
+	msg: &Message,
+) -> Option<Cow<'a, str>> {
 	let mut page = 0;
 	if let Some(i) = msg.message.rfind(' ') {
 		if let Ok(n) = (msg.message[i + 1..]).parse::<usize>() {
@@ -229,16 +358,16 @@ fn list<'a>(bot: &Bot, msg: &Message) -> Option<Cow<'a, str>> {
 		}
 	}
 
-	if page >= bot.list.len() {
-		page = bot.list.len() - 1;
+	if page >= pages.len() {
+		page = pages.len() - 1;
 	}
 
-	let page_s = bot.list[page].clone();
-	let res = if bot.list.len() > 1 {
+	let page_s = pages[page].clone();
+	let res = if pages.len() > 1 {
 		format!(
 			"Page {}/{}, use [i]{}list <page>[/i] to show more.{}",
 			page + 1,
-			bot.list.len(),
+			pages.len(),
 			crate::escape_bb(&bot.settings.prefix),
 			page_s, //crate::escape_bb(&page_s),
 		)
@@ -249,30 +378,88 @@ fn list<'a>(bot: &Bot, msg: &Message) -> Option<Cow<'a, str>> {
 	Some(res.into())
 }
 
-pub fn init_list(bot: &mut Bot) {
+/// Append a readable version of a matcher to `res`.
+fn describe(m: &Matcher, res: &mut String) {
+	match m {
+		Matcher::Contains(c) => {
+			res.push_str(&c.text);
+			let mut options = Vec::new();
+			if c.mode != MatchMode::Word {
+				options.push(c.mode.name());
+			}
+			if c.ignore_case {
+				options.push("ignore case");
+			}
+			if !options.is_empty() {
+				let _ = write!(res, " ({})", options.join(", "));
+			}
+		}
+		Matcher::Regex(r) => {
+			let mut r = r.as_str().to_string();
+			r = r.replace(&['^', '$'][..], "");
+			r = r.replace("\\b", "");
+
+			r = r.replace("\\\\", "\\");
+			r = r.replace("\\.", ".");
+			res.push_str(&r);
+		}
+		Matcher::Mode(m) => {
+			let _ = write!(res, " (only in {} mode)", Reaction::get_mode(m));
+		}
+		Matcher::Uid(_) => res.push_str(" (only for some users)"),
+		Matcher::Name(r) => {
+			let name = crate::escape_bb(r.as_str());
+			let _ = write!(res, " (only for names like {})", name);
+		}
+		Matcher::ServerGroup(groups) => {
+			let groups = groups
+				.iter()
+				.map(|g| g.to_string())
+				.collect::<Vec<_>>()
+				.join(", ");
+			let _ = write!(res, " (only for server groups {})", groups);
+		}
+		Matcher::Not(m) => {
+			res.push_str(" not ");
+			let mut inner = String::new();
+			describe(m, &mut inner);
+			// Only add parentheses if the description has none
+			if matches!(
+				**m,
+				Matcher::Contains(_) | Matcher::Regex(_) | Matcher::Not(_)
+			) {
+				let _ = write!(res, "({})", inner.trim());
+			} else {
+				res.push_str(inner.trim());
+			}
+		}
+		Matcher::Any(ms) => describe_group(ms, " or ", res),
+		Matcher::All(ms) => describe_group(ms, " and ", res),
+	}
+}
+
+fn describe_group(ms: &[Matcher], separator: &str, res: &mut String) {
+	res.push_str(" (");
+	for (i, m) in ms.iter().enumerate() {
+		if i != 0 {
+			res.push_str(separator);
+		}
+		let mut inner = String::new();
+		describe(m, &mut inner);
+		res.push_str(inner.trim());
+	}
+	res.push(')');
+}
+
+/// Describe all actions, split into pages.
+pub fn list_pages(actions: &ActionList) -> Vec<String> {
 	let mut matchers = Vec::new();
-	for a in &bot.actions.0 {
+	for a in &actions.0 {
 		let mut res = String::new();
 		for m in &a.matchers {
-			match m {
-				Matcher::Regex(r) => {
-					let mut r = r.as_str().to_string();
-					r = r.replace(&['^', '$'][..], "");
-					r = r.replace("\\b", "");
-
-					r = r.replace("\\\\", "\\");
-					r = r.replace("\\.", ".");
-					res.push_str(&r);
-				}
-				Matcher::Mode(m) => {
-					let _ = write!(
-						res,
-						" (only in {} mode)",
-						Reaction::get_mode(m)
-					);
-				}
-			}
+			describe(m, &mut res);
 		}
+		let res = res.trim().to_string();
 		matchers.push(res);
 	}
 	matchers.sort_unstable();
@@ -290,5 +477,237 @@ pub fn init_list(bot: &mut Bot) {
 		cur.push_str(&m);
 	}
 
-	bot.list = res;
+	res
+}
+
+#[cfg(test)]
+mod tests {
+	use tsclientlib::{MessageTarget, ServerGroupId};
+
+	use super::*;
+	use crate::permission::Permission;
+	use crate::test_util::{
+		test_client, test_message, FakeConnection, TestDir,
+	};
+
+	fn bot(dir: &TestDir) -> Bot {
+		let mut bot = Bot::new();
+		bot.base_dir = dir.to_path_buf();
+		bot.settings_path = Some(bot.base_dir.join("settings.toml"));
+		crate::load_settings(&mut bot).unwrap();
+		bot
+	}
+
+	/// Send a message from client 2 and return the response.
+	fn send(bot: &mut Bot, con: &mut FakeConnection, msg: &str) -> String {
+		let msg = test_message(MessageTarget::Channel, msg);
+		let res = match bot.actions.handle(bot, con, &msg, 0) {
+			Handled::Nothing => String::new(),
+			Handled::Response(r) => r.into_owned(),
+			Handled::NotAllowed => bot.settings.not_allowed.clone(),
+			Handled::Pending(..) => panic!("Unexpected script"),
+		};
+		crate::check_reload(bot);
+		res
+	}
+
+	#[test]
+	fn help() {
+		let dir = TestDir::new("help");
+		let mut bot = bot(&dir);
+		bot.settings.prefix = "!".into();
+		bot.actions = Default::default();
+		crate::plugin::add_commands(&mut bot);
+		let mut con = FakeConnection::new();
+		assert!(send(&mut bot, &mut con, "!help").contains("!add"));
+		assert_eq!(send(&mut bot, &mut con, ".help"), "");
+	}
+
+	#[test]
+	fn add_and_del() {
+		let dir = TestDir::new("add_and_del");
+		let mut bot = bot(&dir);
+		let mut con = FakeConnection::new();
+		assert!(send(&mut bot, &mut con, ".add").starts_with("Usage"));
+		assert_eq!(
+			send(&mut bot, &mut con, ".add Hi {invoker.name} on hello"),
+			""
+		);
+		assert_eq!(send(&mut bot, &mut con, ".add Hey on hello"), "");
+		assert_eq!(send(&mut bot, &mut con, "hello there"), "Hi Alice");
+		assert!(send(&mut bot, &mut con, ".list").contains("hello"));
+
+		assert!(send(&mut bot, &mut con, ".add {unknown} on x")
+			.starts_with("Invalid action"));
+
+		assert_eq!(
+			send(&mut bot, &mut con, ".del hello"),
+			"Removed 2 elements"
+		);
+		assert_eq!(send(&mut bot, &mut con, "hello there"), "");
+		assert_eq!(
+			send(&mut bot, &mut con, ".del hello"),
+			"Removed 0 elements"
+		);
+	}
+
+	#[test]
+	fn add_options() {
+		let dir = TestDir::new("add_options");
+		let mut bot = bot(&dir);
+		let mut con = FakeConnection::new();
+		assert_eq!(
+			send(&mut bot, &mut con, ".add --regex Hi {1} on ^hi (\\w+)$"),
+			""
+		);
+		assert_eq!(send(&mut bot, &mut con, "hi bob"), "Hi bob");
+		assert_eq!(send(&mut bot, &mut con, "oh hi bob"), "");
+
+		assert!(send(&mut bot, &mut con, ".add --regex Hi on (")
+			.starts_with("Invalid action"));
+		assert!(send(&mut bot, &mut con, ".add --chat nowhere Hi on x")
+			.starts_with("Invalid action"));
+		assert_eq!(
+			send(&mut bot, &mut con, ".add --foo Hi on x"),
+			"Unknown option --foo"
+		);
+
+		assert_eq!(
+			send(&mut bot, &mut con, ".add --chat server Yes on ping"),
+			""
+		);
+		assert_eq!(send(&mut bot, &mut con, "ping"), "");
+
+		assert_eq!(
+			send(
+				&mut bot,
+				&mut con,
+				".add --match prefix --ignore-case Yo on !yo"
+			),
+			""
+		);
+		assert_eq!(send(&mut bot, &mut con, "!YO there"), "Yo");
+		assert!(send(&mut bot, &mut con, ".list")
+			.contains("!yo (prefix, ignore case)"));
+
+		assert_eq!(
+			send(&mut bot, &mut con, ".add --append Other on nothing"),
+			"No action with this trigger found"
+		);
+		assert_eq!(send(&mut bot, &mut con, ".add A on alt"), "");
+		assert_eq!(send(&mut bot, &mut con, ".add --append B on alt"), "");
+		let mut responses = std::collections::HashSet::new();
+		for _ in 0..50 {
+			responses.insert(send(&mut bot, &mut con, "alt"));
+		}
+		assert_eq!(responses.len(), 2);
+
+		// Commands need an explicit permission
+		assert_eq!(
+			send(&mut bot, &mut con, ".add --shell echo hi on cmd"),
+			bot.settings.not_allowed
+		);
+		assert!(!send(&mut bot, &mut con, ".list").contains("cmd"));
+
+		assert_eq!(
+			send(&mut bot, &mut con, ".del ^hi (\\w+)$"),
+			"Removed 1 element"
+		);
+		assert_eq!(send(&mut bot, &mut con, "hi bob"), "");
+	}
+
+	#[test]
+	fn list_matcher_tree() {
+		let dir = TestDir::new("list_matcher_tree");
+		let mut bot = bot(&dir);
+		let def: ActionDefinition = toml::from_str(
+			r#"
+			contains = "hi"
+			chat = "channel"
+			not = { from_name = "^[b]ob$" }
+			any_of = [{ contains = "a" }, { contains = "b", ignore_case = true }]
+			"#,
+		)
+		.unwrap();
+		bot.actions = ActionList(vec![def.to_action().unwrap()]);
+		assert_eq!(
+			super::list_pages(&bot.actions),
+			vec![
+				"\nhi (only in channel mode) not (only for names like \
+				 ^\\[b]ob$) (a or b (ignore case))"
+			]
+		);
+	}
+
+	#[test]
+	fn list_pages() {
+		let dir = TestDir::new("list_pages");
+		let mut bot = bot(&dir);
+		for i in 0..100 {
+			let a = ActionDefinition {
+				matcher: MatcherDefinition {
+					contains: Some(format!("a long trigger number {}", i)),
+					..Default::default()
+				},
+				..Default::default()
+			};
+			bot.actions.0.push(a.to_action().unwrap());
+		}
+		crate::plugin::reload(&mut bot);
+		let list = super::list_pages(&bot.actions);
+		let pages = list.len();
+		assert!(pages > 1);
+		assert!(list.iter().all(|p| p.len() <= 1000));
+
+		let mut con = FakeConnection::new();
+		let first = send(&mut bot, &mut con, ".list");
+		assert!(first.starts_with(&format!("Page 1/{}", pages)));
+		assert_eq!(send(&mut bot, &mut con, ".list 1"), first);
+		let second = send(&mut bot, &mut con, ".list 2");
+		assert!(second.starts_with(&format!("Page 2/{}", pages)));
+		// Pages after the end show the last page
+		let last = send(&mut bot, &mut con, ".list 1000");
+		assert!(last.starts_with(&format!("Page {0}/{0}", pages)));
+	}
+
+	#[test]
+	fn quit_permission() {
+		let dir = TestDir::new("quit_permission");
+		let mut bot = bot(&dir);
+		bot.settings.permissions.insert(
+			"quit".into(),
+			Permission {
+				uids: Vec::new(),
+				server_groups: vec![6],
+			},
+		);
+		let mut con = FakeConnection::new();
+		con.clients.push(test_client());
+
+		assert_eq!(send(&mut bot, &mut con, ".quit"), bot.settings.not_allowed);
+		assert!(!con.disconnected);
+		assert!(!bot.should_quit.get());
+
+		con.clients[1].server_groups.push(ServerGroupId(6));
+		assert_eq!(send(&mut bot, &mut con, ".quit"), "");
+		assert!(con.disconnected);
+		assert!(bot.should_quit.get());
+	}
+
+	#[test]
+	fn old_braces() {
+		let dir = TestDir::new("old_braces");
+		let file = dir.join("dynamic.toml");
+		fs::write(
+			&file,
+			"[[on_message]]\nregex = \"^json$\"\nresponse = \"{ \\\"a\\\": 1 \
+			 }\"\n",
+		)
+		.unwrap();
+		// Braces from before placeholders existed are escaped
+		let mut bot = bot(&dir);
+		let mut con = FakeConnection::new();
+		assert_eq!(send(&mut bot, &mut con, "json"), "{ \"a\": 1 }");
+		assert!(fs::read_to_string(&file).unwrap().contains("{{"));
+	}
 }
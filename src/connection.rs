@@ -0,0 +1,258 @@
+use anyhow::{format_err, Result};
+use tsclientlib::{
+	data, ChannelId, ClientId, Connection, DisconnectOptions, Invoker,
+	MessageTarget, OutCommandExt, ServerGroupId, UidBuf,
+};
+
+use crate::ChannelDefinition;
+
+/// The information about a client which is used by the bot.
+#[derive(Clone, Debug)]
+pub struct ClientInfo {
+	pub id: ClientId,
+	pub name: String,
+	pub uid: Option<UidBuf>,
+	pub channel: ChannelId,
+	pub server_groups: Vec<ServerGroupId>,
+}
+
+/// The information about a channel which is used by the bot.
+#[derive(Clone, Debug)]
+pub struct ChannelInfo {
+	pub id: ChannelId,
+	pub name: String,
+	/// `ChannelId(0)` if the channel has no parent.
+	pub parent: ChannelId,
+}
+
+/// An event of a connection, which is handled by [`Bot::run_with`].
+///
+/// [`Bot::run_with`]: crate::Bot::run_with
+#[derive(Clone, Debug)]
+pub enum BotEvent {
+	/// A client sent a message. Messages of the bot itself are ignored.
+	Message {
+		target: MessageTarget,
+		invoker: Invoker,
+		message: String,
+	},
+	/// A client connected to the server.
+	Join(ClientInfo),
+	/// A client disconnected from the server.
+	Leave(ClientInfo),
+	/// A client entered the channel of the bot.
+	ChannelEnter(ClientInfo),
+	/// A client left the channel of the bot.
+	ChannelLeave(ClientInfo),
+}
+
+/// The parts of a server connection which are used by actions and builtins.
+///
+/// This is implemented for [`Connection`] and for the connection of the
+/// simulation mode.
+pub trait BotConnection {
+	/// The id of the client of the bot.
+	fn own_client(&self) -> Result<ClientId>;
+	fn client(&self, id: ClientId) -> Option<ClientInfo>;
+	/// All clients on the server.
+	fn clients(&self) -> Vec<ClientInfo>;
+	fn channel(&self, id: ChannelId) -> Option<ChannelInfo>;
+	/// All channels on the server.
+	fn channels(&self) -> Vec<ChannelInfo>;
+
+	fn send_message(
+		&mut self,
+		target: MessageTarget,
+		message: &str,
+	) -> Result<()>;
+	/// Move the bot into a channel.
+	fn move_to(&mut self, channel: ChannelId) -> Result<()>;
+	/// Kick a client from its channel or from the server.
+	fn kick(
+		&mut self,
+		client: ClientId,
+		from_server: bool,
+		reason: &str,
+	) -> Result<()>;
+	fn disconnect(&mut self) -> Result<()>;
+}
+
+/// A connection where the client of an event can be found, also if it
+/// already left the server.
+pub(crate) struct EventConnection<'a> {
+	pub con: &'a mut dyn BotConnection,
+	pub client: &'a ClientInfo,
+}
+
+impl BotConnection for EventConnection<'_> {
+	fn own_client(&self) -> Result<ClientId> { self.con.own_client() }
+
+	fn client(&self, id: ClientId) -> Option<ClientInfo> {
+		if id == self.client.id {
+			Some(self.client.clone())
+		} else {
+			self.con.client(id)
+		}
+	}
+
+	fn clients(&self) -> Vec<ClientInfo> { self.con.clients() }
+	fn channel(&self, id: ChannelId) -> Option<ChannelInfo> {
+		self.con.channel(id)
+	}
+	fn channels(&self) -> Vec<ChannelInfo> { self.con.channels() }
+
+	fn send_message(
+		&mut self,
+		target: MessageTarget,
+		message: &str,
+	) -> Result<()> {
+		self.con.send_message(target, message)
+	}
+
+	fn move_to(&mut self, channel: ChannelId) -> Result<()> {
+		self.con.move_to(channel)
+	}
+
+	fn kick(
+		&mut self,
+		client: ClientId,
+		from_server: bool,
+		reason: &str,
+	) -> Result<()> {
+		self.con.kick(client, from_server, reason)
+	}
+
+	fn disconnect(&mut self) -> Result<()> { self.con.disconnect() }
+}
+
+/// Find a channel by id or by its path, e.g. `My Channel/Nested`.
+pub fn find_channel(
+	con: &dyn BotConnection,
+	channel: &ChannelDefinition,
+) -> Option<ChannelId> {
+	match channel {
+		ChannelDefinition::Id(id) => con.channel(ChannelId(*id)).map(|c| c.id),
+		ChannelDefinition::Name(path) => {
+			let channels = con.channels();
+			let mut res = ChannelId(0);
+			for name in path.split('/') {
+				res = channels
+					.iter()
+					.find(|c| c.parent == res && c.name == name)?
+					.id;
+			}
+			Some(res)
+		}
+	}
+}
+
+impl From<&data::Client> for ClientInfo {
+	fn from(c: &data::Client) -> Self {
+		Self {
+			id: c.id,
+			name: c.name.clone(),
+			uid: c.uid.clone(),
+			channel: c.channel,
+			server_groups: c.server_groups.clone(),
+		}
+	}
+}
+
+impl From<&data::Channel> for ChannelInfo {
+	fn from(c: &data::Channel) -> Self {
+		Self {
+			id: c.id,
+			name: c.name.clone(),
+			parent: c.parent,
+		}
+	}
+}
+
+impl BotConnection for Connection {
+	fn own_client(&self) -> Result<ClientId> {
+		Ok(self.get_state()?.own_client)
+	}
+
+	fn client(&self, id: ClientId) -> Option<ClientInfo> {
+		self.get_state()
+			.ok()?
+			.clients
+			.get(&id)
+			.map(ClientInfo::from)
+	}
+
+	fn clients(&self) -> Vec<ClientInfo> {
+		match self.get_state() {
+			Ok(state) => state.clients.values().map(ClientInfo::from).collect(),
+			Err(_) => Vec::new(),
+		}
+	}
+
+	fn channel(&self, id: ChannelId) -> Option<ChannelInfo> {
+		self.get_state()
+			.ok()?
+			.channels
+			.get(&id)
+			.map(ChannelInfo::from)
+	}
+
+	fn channels(&self) -> Vec<ChannelInfo> {
+		match self.get_state() {
+			Ok(state) => {
+				state.channels.values().map(ChannelInfo::from).collect()
+			}
+			Err(_) => Vec::new(),
+		}
+	}
+
+	fn send_message(
+		&mut self,
+		target: MessageTarget,
+		message: &str,
+	) -> Result<()> {
+		let state = self.get_state()?;
+		state
+			.send_message(target, message)
+			.send(self)
+			.map_err(|e| format_err!("{}", e))?;
+		Ok(())
+	}
+
+	fn move_to(&mut self, channel: ChannelId) -> Result<()> {
+		let state = self.get_state()?;
+		state
+			.clients
+			.get(&state.own_client)
+			.ok_or_else(|| format_err!("Own client not found"))?
+			.set_channel(channel)
+			.send(self)
+			.map_err(|e| format_err!("{}", e))?;
+		Ok(())
+	}
+
+	fn kick(
+		&mut self,
+		client: ClientId,
+		from_server: bool,
+		reason: &str,
+	) -> Result<()> {
+		let state = self.get_state()?;
+		let client = state
+			.clients
+			.get(&client)
+			.ok_or_else(|| format_err!("Client {} not found", client.0))?;
+		if from_server {
+			client.kick_from_server(reason)
+		} else {
+			client.kick_from_channel(reason)
+		}
+		.send(self)
+		.map_err(|e| format_err!("{}", e))?;
+		Ok(())
+	}
+
+	fn disconnect(&mut self) -> Result<()> {
+		Connection::disconnect(self, DisconnectOptions::new())?;
+		Ok(())
+	}
+}
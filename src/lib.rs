@@ -0,0 +1,1731 @@
+//! A simple TeamSpeak chat bot.
+//!
+//! Create a bot with [`Bot::builder`] and run it with [`Bot::run`]. To use
+//! the bot with another connection, e.g. in tests, implement
+//! [`BotConnection`] and pass its events to [`Bot::run_with`] or single
+//! messages to [`Bot::process_message`].
+
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use futures::prelude::*;
+use futures::stream::FuturesUnordered;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
+use tsclientlib::events::{Event, PropertyId, PropertyValue};
+use tsclientlib::{
+	ChannelId, ClientId, Connection, DisconnectOptions, Identity, Invoker,
+	InvokerRef, MessageTarget, Reason, StreamItem,
+};
+
+/// The delay before the first reconnect attempt.
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+/// The delay between reconnect attempts doubles until it reaches this maximum.
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+/// Pokes can be at most this many characters long.
+const MAX_POKE_LENGTH: usize = 100;
+
+mod action;
+mod builtins;
+pub mod connection;
+mod permission;
+pub mod plugin;
+mod rate_limit;
+mod script;
+mod simulate;
+mod split;
+mod template;
+#[cfg(test)]
+mod test_util;
+mod timer;
+mod wasm;
+mod watch;
+
+pub use crate::action::{
+	ActionDefinition, MatcherDefinition, ResponseDefinition, Trigger,
+};
+pub use crate::permission::Permission;
+pub use crate::rate_limit::{Limit, QueuePolicy};
+pub use crate::timer::TimerDefinition;
+
+use crate::action::{
+	ActionList, Cooldowns, EventActions, Handled, KickFrom, Operation,
+	OperationTarget, Response, ScriptFuture,
+};
+use crate::connection::{BotConnection, BotEvent, ClientInfo, EventConnection};
+use crate::plugin::Plugin;
+use crate::rate_limit::{Limited, RateLimiter};
+use crate::script::Store;
+use crate::template::Captures;
+use crate::timer::Timer;
+use crate::watch::{FileVersion, FileWatcher};
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ActionFile {
+	/// Includes other files.
+	///
+	/// The path is always relative to the current file. Includes will be
+	/// inserted after the declarations in this file.
+	#[serde(default = "Vec::new")]
+	pub include: Vec<String>,
+
+	// This needs to be second for the toml serialization.
+	#[serde(default = "Vec::new")]
+	pub on_message: Vec<ActionDefinition>,
+	/// Triggered when a client connects to the server.
+	#[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
+	pub on_join: Vec<ActionDefinition>,
+	/// Triggered when a client disconnects from the server.
+	#[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
+	pub on_leave: Vec<ActionDefinition>,
+	/// Triggered when a client enters the channel of the bot.
+	#[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
+	pub on_channel_enter: Vec<ActionDefinition>,
+	/// Triggered when a client leaves the channel of the bot.
+	#[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
+	pub on_channel_leave: Vec<ActionDefinition>,
+	/// Triggered periodically or at fixed times.
+	#[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
+	pub on_timer: Vec<TimerDefinition>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum ChannelDefinition {
+	Id(u64),
+	Name(String),
+}
+
+/// The settings of the bot, usually loaded from `settings.toml`.
+///
+/// Fields can be added in new versions, so create settings from
+/// [`Settings::default`] and change the fields.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[non_exhaustive]
+pub struct Settings {
+	/// The file which contains the private key.
+	///
+	/// This will be automatically generated on the first run.
+	///
+	/// # Default
+	/// `private.key`
+	#[serde(default = "default_key_file")]
+	pub key_file: String,
+	/// Dynamically added actions. This file will be overwritten automatically.
+	///
+	/// The actions from this file will be added after the normal actions and
+	/// before the builtins.
+	///
+	/// # Default
+	/// `dynamic.toml`
+	#[serde(default = "default_dynamic_actions")]
+	pub dynamic_actions: String,
+	/// The values which scripts store with `store_set`. This file will be
+	/// overwritten automatically.
+	///
+	/// # Default
+	/// `store.json`
+	#[serde(default = "default_script_store")]
+	pub script_store: String,
+	/// Reload automatically when the settings, included files or the dynamic
+	/// actions change.
+	///
+	/// # Default
+	/// `true`
+	#[serde(default = "default_watch_files")]
+	pub watch_files: bool,
+
+	/// The address of the server to connect to.
+	///
+	/// # Default
+	/// `localhost`
+	#[serde(default = "default_address")]
+	pub address: String,
+	/// The channel on the server to connect to.
+	///
+	/// E.g. 4, "My Channel" or "My Channel/Nested"
+	///
+	/// # Default
+	/// `None`
+	pub channel: Option<ChannelDefinition>,
+	/// The name of the bot.
+	///
+	/// # Default
+	/// `SimpleBot`
+	#[serde(default = "default_name")]
+	pub name: String,
+	/// The disconnect message of the bot.
+	///
+	/// # Default
+	/// `Disconnecting`
+	#[serde(default = "default_disconnect_message")]
+	pub disconnect_message: String,
+	/// How many messages can be sent per second, must not be zero.
+	///
+	/// If this limit is exceeded, messages are ignored or responses are
+	/// queued, depending on `rate_limit_policy`.
+	///
+	/// # Default
+	/// `2`
+	#[serde(default = "default_rate_limit")]
+	pub rate_limit: u8,
+	/// How many responses a single user can get.
+	///
+	/// # Default
+	/// No limit
+	pub user_rate_limit: Option<Limit>,
+	/// How many responses can be sent to a single chat.
+	///
+	/// # Default
+	/// No limit
+	pub target_rate_limit: Option<Limit>,
+	/// Ignore a user for some time after hitting the `user_rate_limit` this
+	/// many times in a row.
+	///
+	/// # Default
+	/// Never ignore users
+	pub rate_limit_ignore_after: Option<u32>,
+	/// For how many seconds a user gets ignored.
+	///
+	/// The time doubles every time the user is ignored again.
+	///
+	/// # Default
+	/// `60`
+	#[serde(default = "default_rate_limit_ignore_time")]
+	pub rate_limit_ignore_time: u64,
+	/// What happens when the global or target rate limit is reached: Either
+	/// `drop` to ignore incoming messages, `queue` to queue responses and
+	/// drop new ones if the queue is full or `drop-oldest` to drop the oldest
+	/// queued response if the queue is full.
+	///
+	/// With `drop`, the parts of long responses are still queued if they
+	/// cannot be sent immediately.
+	///
+	/// # Default
+	/// `drop`
+	#[serde(default)]
+	pub rate_limit_policy: QueuePolicy,
+	/// How many responses can be queued.
+	///
+	/// # Default
+	/// `10`
+	#[serde(default = "default_rate_limit_queue_size")]
+	pub rate_limit_queue_size: usize,
+	/// The maximum length of a message in characters, longer responses are
+	/// split into multiple messages.
+	///
+	/// Pokes are split at 100 characters, the limit of the server.
+	///
+	/// # Default
+	/// `1024`
+	#[serde(default = "default_max_message_length")]
+	pub max_message_length: usize,
+	/// Into how many messages a response can be split at most, the rest gets
+	/// cut off.
+	///
+	/// # Default
+	/// `5`
+	#[serde(default = "default_max_message_parts")]
+	pub max_message_parts: usize,
+	/// After how many seconds a command or shell script gets killed.
+	///
+	/// Can be overwritten per action with `timeout`.
+	///
+	/// # Default
+	/// `10`
+	#[serde(default = "default_script_timeout")]
+	pub script_timeout: u64,
+	/// The maximum length of the output of a script in bytes, the rest gets
+	/// cut off.
+	///
+	/// Can be overwritten per action with `max_output`.
+	///
+	/// # Default
+	/// `4096`
+	#[serde(default = "default_script_max_output")]
+	pub script_max_output: usize,
+	/// How much fuel a wasm plugin can use to handle a message, roughly the
+	/// number of executed instructions.
+	///
+	/// # Default
+	/// `10000000`
+	#[serde(default = "default_wasm_fuel")]
+	pub wasm_fuel: u64,
+	/// How much memory a wasm plugin can use in bytes.
+	///
+	/// # Default
+	/// `16777216` (16 MiB)
+	#[serde(default = "default_wasm_max_memory")]
+	pub wasm_max_memory: usize,
+
+	/// The prefix for builtin commands.
+	///
+	/// # Default
+	/// `.`
+	#[serde(default = "default_prefix")]
+	pub prefix: String,
+
+	/// Restrict builtin commands to certain users.
+	///
+	/// Maps the name of a builtin (e.g. `quit`) or plugin command to the
+	/// users who are allowed to use it. Everything that is not listed here
+	/// can be used by everyone.
+	#[serde(default = "HashMap::new")]
+	pub permissions: HashMap<String, Permission>,
+	/// Restrict named actions to certain users.
+	///
+	/// Like `permissions`, but maps the `name` of an action to the users who
+	/// are allowed to use it.
+	#[serde(default = "HashMap::new")]
+	pub action_permissions: HashMap<String, Permission>,
+	/// The response if someone is not allowed to use a command.
+	///
+	/// # Default
+	/// `You are not allowed to do that.`
+	#[serde(default = "default_not_allowed")]
+	pub not_allowed: String,
+
+	#[serde(default = "Default::default")]
+	pub actions: ActionFile,
+}
+
+#[derive(Debug)]
+pub struct Bot {
+	base_dir: PathBuf,
+	/// If `None`, the settings are not loaded from a file.
+	settings_path: Option<PathBuf>,
+	/// Actions which are added after the actions from the settings.
+	extra_actions: ActionFile,
+	/// The files which the settings and actions were loaded from.
+	files: Vec<PathBuf>,
+	/// The versions of `files` when they were loaded.
+	file_versions: Vec<FileVersion>,
+	actions: ActionList,
+	events: EventActions,
+	timers: Vec<Timer>,
+	settings: Settings,
+	rate_limiter: RateLimiter,
+	/// Responses which wait for the rate limit.
+	queue: VecDeque<QueuedResponse>,
+	/// Operations which are performed later.
+	delayed: Vec<DelayedOperations>,
+	/// The values of scripts.
+	store: Arc<Mutex<Store>>,
+	/// Running cooldowns, they are kept when reloading.
+	cooldowns: Cooldowns,
+	/// Plugins which add commands and react to events.
+	plugins: Vec<Box<dyn Plugin>>,
+	should_reload: Cell<bool>,
+	/// Set when the bot was asked to disconnect, so it will not reconnect.
+	should_quit: Cell<bool>,
+	/// Incremented on every reload of the actions.
+	generation: u64,
+	/// Read messages from stdin instead of connecting to a server.
+	simulate: bool,
+	/// How much of the connection should be logged.
+	verbose: u8,
+}
+
+/// Configure and create a [`Bot`].
+#[derive(Debug, Default)]
+pub struct BotBuilder {
+	settings_path: Option<PathBuf>,
+	settings: Option<Settings>,
+	base_dir: Option<PathBuf>,
+	actions: ActionFile,
+	plugins: Vec<Box<dyn Plugin>>,
+	simulate: bool,
+	verbose: u8,
+}
+
+#[derive(Clone, Debug)]
+pub struct Message<'a> {
+	pub target: MessageTarget,
+	pub invoker: InvokerRef<'a>,
+	pub message: &'a str,
+}
+
+/// A copy of a [`Message`] which can be kept while a script is running.
+#[derive(Clone, Debug)]
+pub struct OwnedMessage {
+	target: MessageTarget,
+	invoker: Invoker,
+	message: String,
+}
+
+/// The output of a script, together with the message which started it.
+#[derive(Debug)]
+struct ScriptResult {
+	message: OwnedMessage,
+	/// The index of the action which started the script.
+	action: usize,
+	/// The generation of the action list when the script was started.
+	generation: u64,
+	output: Option<Vec<Operation>>,
+	trigger: Trigger,
+}
+
+/// Operations of a script which should be performed at a later time.
+#[derive(Debug)]
+struct DelayedOperations {
+	at: Instant,
+	result: ScriptResult,
+}
+
+/// A response which could not be sent yet because of rate limiting.
+#[derive(Clone, Debug)]
+struct QueuedResponse {
+	target: MessageTarget,
+	message: String,
+}
+
+/// Why the connection to the server ended.
+enum ConnectionEnd {
+	/// The bot should quit.
+	Quit,
+	/// The connection was lost, the bot should reconnect.
+	Lost,
+	/// Ctrl + c was pressed, the bot should disconnect and quit.
+	Interrupted,
+}
+
+type Scripts = FuturesUnordered<Pin<Box<dyn Future<Output = ScriptResult>>>>;
+
+type EventsFuture<'a> =
+	Pin<Box<dyn Future<Output = Option<Result<Vec<BotEvent>>>> + 'a>>;
+
+/// A connection which the event loop of the bot gets its events from.
+trait EventSource {
+	fn connection(&mut self) -> &mut dyn BotConnection;
+	/// Wait for the next events, `None` if the connection ended.
+	fn next_events(&mut self) -> EventsFuture<'_>;
+}
+
+/// A [`BotConnection`] together with a stream of its events.
+struct StreamSource<'a, S> {
+	con: &'a mut dyn BotConnection,
+	events: S,
+}
+
+impl Bot {
+	fn new() -> Self {
+		Self {
+			base_dir: PathBuf::new(),
+			settings_path: None,
+			extra_actions: Default::default(),
+			files: Vec::new(),
+			file_versions: Vec::new(),
+			actions: Default::default(),
+			events: Default::default(),
+			timers: Default::default(),
+			settings: Default::default(),
+			rate_limiter: Default::default(),
+			queue: Default::default(),
+			delayed: Default::default(),
+			store: Default::default(),
+			plugins: vec![Box::new(builtins::Builtins::default())],
+			cooldowns: Default::default(),
+			should_reload: Default::default(),
+			should_quit: Default::default(),
+			generation: 0,
+			simulate: false,
+			verbose: 0,
+		}
+	}
+
+	pub fn builder() -> BotBuilder { BotBuilder::default() }
+
+	pub fn settings(&self) -> &Settings { &self.settings }
+
+	/// Register a plugin.
+	///
+	/// The commands of the plugin are added when the settings are loaded the
+	/// next time.
+	pub fn add_plugin<P: Plugin + 'static>(&mut self, plugin: P) {
+		self.plugins.push(Box::new(plugin));
+	}
+
+	/// Connect to the server and handle events until the bot is stopped.
+	///
+	/// Reconnects when the connection is lost. In simulation mode, messages
+	/// are read from stdin instead.
+	pub async fn run(&mut self) -> Result<()> {
+		let mut watcher = self.file_watcher();
+		if self.simulate {
+			return simulate::run(self, &mut watcher).await;
+		}
+
+		// Load private key
+		let file = Path::new(&self.settings.key_file);
+		let file = if file.is_absolute() {
+			file.to_path_buf()
+		} else {
+			self.base_dir.join(&self.settings.key_file)
+		};
+		let private_key = match fs::read(&file) {
+			Ok(r) => tsproto_types::crypto::EccKeyPrivP256::import(&r)?,
+			_ => {
+				// Create new key
+				let key = tsproto_types::crypto::EccKeyPrivP256::create();
+
+				// Create directory
+				if let Err(error) = fs::create_dir_all(&self.base_dir) {
+					error!(%error, "Failed to create config dictionary");
+				}
+				// Write to file
+				if let Err(error) = fs::write(&file, key.to_short()) {
+					warn!(%error, "file" = ?file.to_str(), "Failed to store the private key, the server \
+						identity will not be the same in the next run");
+				}
+
+				key
+			}
+		};
+		let identity = Identity::new(private_key, 0);
+
+		let mut delay = RECONNECT_MIN_DELAY;
+		loop {
+			let mut connected = false;
+			match run_connection(
+				self,
+				&mut watcher,
+				identity.clone(),
+				&mut connected,
+			)
+			.await
+			{
+				Ok(ConnectionEnd::Quit | ConnectionEnd::Interrupted) => break,
+				Ok(ConnectionEnd::Lost) => {
+					warn!("Lost connection to the server")
+				}
+				Err(error) => error!(%error, "Connection failed"),
+			}
+
+			if connected {
+				delay = RECONNECT_MIN_DELAY;
+			}
+			// Add up to 50 % jitter so not all bots reconnect at the same time
+			let wait = delay.mul_f64(rand::thread_rng().gen_range(0.5..1.5));
+			info!(?wait, "Reconnecting");
+			tokio::select! {
+				_ = tokio::signal::ctrl_c() => { break; }
+				_ = tokio::time::sleep(wait) => {}
+			}
+			delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+		}
+
+		Ok(())
+	}
+
+	/// Handle the `events` of a connection until they end or the bot is
+	/// stopped with ctrl + c.
+	///
+	/// Timers, scripts, queued responses and changed files are handled like
+	/// with [`Bot::run`]. When the events end, the bot waits for all running
+	/// scripts and queued responses before returning.
+	pub async fn run_with<S>(
+		&mut self,
+		con: &mut dyn BotConnection,
+		events: S,
+	) -> Result<()>
+	where
+		S: Stream<Item = BotEvent> + Unpin,
+	{
+		let mut watcher = self.file_watcher();
+		let mut source = StreamSource { con, events };
+		let mut scripts = Scripts::new();
+		let end =
+			event_loop(self, &mut source, &mut watcher, &mut scripts).await?;
+		if let ConnectionEnd::Interrupted = end {
+			return Ok(());
+		}
+		finish(self, source.con, &mut scripts).await;
+		Ok(())
+	}
+
+	/// Watch the files of the settings if this is enabled.
+	fn file_watcher(&self) -> Option<FileWatcher> {
+		if !self.settings.watch_files {
+			return None;
+		}
+		match FileWatcher::new() {
+			Ok(mut w) => {
+				w.watch(&self.files);
+				Some(w)
+			}
+			Err(error) => {
+				error!(%error, "Failed to watch files");
+				None
+			}
+		}
+	}
+
+	/// Handle a message which was received on `con`.
+	///
+	/// Like messages from a connection, messages from the bot itself and
+	/// messages which hit the rate limit are ignored.
+	///
+	/// Waits until all started scripts finished and all responses are sent,
+	/// then reloads the settings if that was requested.
+	///
+	/// Operations which a plugin delays are not awaited, they are performed
+	/// by [`Bot::process_delayed`] once they are due.
+	pub async fn process_message(
+		&mut self,
+		con: &mut dyn BotConnection,
+		msg: &Message<'_>,
+	) {
+		let mut scripts = Scripts::new();
+		handle_chat_message(self, con, &mut scripts, msg);
+		finish(self, con, &mut scripts).await;
+	}
+
+	/// The time when the next delayed operations are due.
+	pub fn next_delayed(&self) -> Option<Instant> {
+		self.delayed.iter().map(|d| d.at).min()
+	}
+
+	/// Perform the delayed operations which are due on `con`.
+	///
+	/// Waits for the started scripts and responses like
+	/// [`Bot::process_message`].
+	pub async fn process_delayed(&mut self, con: &mut dyn BotConnection) {
+		let mut scripts = Scripts::new();
+		run_delayed(self, con, &mut scripts);
+		finish(self, con, &mut scripts).await;
+	}
+}
+
+impl BotBuilder {
+	/// Load the settings from this file, also when reloading.
+	///
+	/// Relative paths in the settings are relative to the directory of the
+	/// file, if no `base_dir` is set.
+	pub fn settings_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+		self.settings_path = Some(path.into());
+		self
+	}
+
+	/// Use these settings.
+	///
+	/// If a settings file is set as well, these are only used if the file
+	/// does not exist.
+	pub fn settings(mut self, settings: Settings) -> Self {
+		self.settings = Some(settings);
+		self
+	}
+
+	/// The directory for relative paths in the settings.
+	pub fn base_dir<P: Into<PathBuf>>(mut self, path: P) -> Self {
+		self.base_dir = Some(path.into());
+		self
+	}
+
+	/// Add actions after the actions from the settings.
+	pub fn actions(mut self, actions: ActionFile) -> Self {
+		self.actions = actions;
+		self
+	}
+
+	/// Register a plugin.
+	pub fn plugin<P: Plugin + 'static>(mut self, plugin: P) -> Self {
+		self.plugins.push(Box::new(plugin));
+		self
+	}
+
+	/// Read messages from stdin instead of connecting to a server when
+	/// running the bot.
+	pub fn simulate(mut self, simulate: bool) -> Self {
+		self.simulate = simulate;
+		self
+	}
+
+	/// Log commands (`1`), packets (`2`) and udp packets (`3`) of the
+	/// connection.
+	pub fn verbose(mut self, verbose: u8) -> Self {
+		self.verbose = verbose;
+		self
+	}
+
+	/// Create the bot and load its settings and actions.
+	pub fn build(self) -> Result<Bot> {
+		let mut bot = Bot::new();
+		bot.base_dir = match self.base_dir {
+			Some(r) => r,
+			None => self
+				.settings_path
+				.as_deref()
+				.and_then(Path::parent)
+				.map(|p| p.into())
+				.unwrap_or_default(),
+		};
+		bot.settings_path = self.settings_path;
+		if let Some(settings) = self.settings {
+			bot.settings = settings;
+		}
+		bot.extra_actions = self.actions;
+		bot.plugins.extend(self.plugins);
+		bot.simulate = self.simulate;
+		bot.verbose = self.verbose;
+		load_settings(&mut bot)?;
+		Ok(bot)
+	}
+}
+
+impl<'a> From<&Message<'a>> for OwnedMessage {
+	fn from(msg: &Message<'a>) -> Self {
+		Self {
+			target: msg.target,
+			invoker: Invoker {
+				name: msg.invoker.name.into(),
+				id: msg.invoker.id,
+				uid: msg.invoker.uid.map(|u| u.to_owned()),
+			},
+			message: msg.message.into(),
+		}
+	}
+}
+
+impl OwnedMessage {
+	fn as_message(&self) -> Message<'_> {
+		Message {
+			target: self.target,
+			invoker: self.invoker.as_ref(),
+			message: &self.message,
+		}
+	}
+}
+
+impl Default for Settings {
+	fn default() -> Self {
+		Self {
+			key_file: default_key_file(),
+			dynamic_actions: default_dynamic_actions(),
+			script_store: default_script_store(),
+			watch_files: default_watch_files(),
+
+			address: default_address(),
+			channel: None,
+			name: default_name(),
+			disconnect_message: default_disconnect_message(),
+			rate_limit: default_rate_limit(),
+			user_rate_limit: None,
+			target_rate_limit: None,
+			rate_limit_ignore_after: None,
+			rate_limit_ignore_time: default_rate_limit_ignore_time(),
+			rate_limit_policy: QueuePolicy::Drop,
+			rate_limit_queue_size: default_rate_limit_queue_size(),
+			max_message_length: default_max_message_length(),
+			max_message_parts: default_max_message_parts(),
+			script_timeout: default_script_timeout(),
+			script_max_output: default_script_max_output(),
+			wasm_fuel: default_wasm_fuel(),
+			wasm_max_memory: default_wasm_max_memory(),
+			prefix: default_prefix(),
+			permissions: HashMap::new(),
+			action_permissions: HashMap::new(),
+			not_allowed: default_not_allowed(),
+
+			actions: Default::default(),
+		}
+	}
+}
+
+fn default_key_file() -> String { "private.key".into() }
+
+fn default_address() -> String { "localhost".into() }
+fn default_name() -> String { "SimpleBot".into() }
+fn default_disconnect_message() -> String { "Disconnecting".into() }
+fn default_rate_limit() -> u8 { 2 }
+fn default_rate_limit_ignore_time() -> u64 { 60 }
+fn default_rate_limit_queue_size() -> usize { 10 }
+fn default_max_message_length() -> usize { 1024 }
+fn default_max_message_parts() -> usize { 5 }
+fn default_script_timeout() -> u64 { 10 }
+fn default_script_max_output() -> usize { 4096 }
+fn default_wasm_fuel() -> u64 { 10_000_000 }
+fn default_wasm_max_memory() -> usize { 16 * 1024 * 1024 }
+fn default_prefix() -> String { ".".into() }
+fn default_not_allowed() -> String { "You are not allowed to do that.".into() }
+fn default_dynamic_actions() -> String { "dynamic.toml".into() }
+fn default_script_store() -> String { "store.json".into() }
+fn default_watch_files() -> bool { true }
+
+/// Connect to the server and handle events until the connection ends.
+///
+/// `connected` is set when the connection was established successfully.
+async fn run_connection(
+	bot: &mut Bot,
+	watcher: &mut Option<FileWatcher>,
+	identity: Identity,
+	connected: &mut bool,
+) -> Result<ConnectionEnd> {
+	let mut con_config = Connection::build(bot.settings.address.clone())
+		.identity(identity)
+		.name(bot.settings.name.clone())
+		.log_commands(bot.verbose >= 1)
+		.log_packets(bot.verbose >= 2)
+		.log_udp_packets(bot.verbose >= 3);
+
+	match &bot.settings.channel {
+		Some(ChannelDefinition::Id(channel)) => {
+			con_config = con_config.channel_id(ChannelId(*channel));
+		}
+		Some(ChannelDefinition::Name(channel)) => {
+			con_config = con_config.channel(channel.clone());
+		}
+		_ => {}
+	}
+
+	// Connect
+	info!(address = bot.settings.address.as_str(), "Connecting");
+	let mut con = con_config.connect()?;
+	let mut events = con
+		.events()
+		.try_filter(|e| future::ready(matches!(e, StreamItem::BookEvents(_))));
+	let r = tokio::select! {
+		// Wait for ctrl + c, also while connecting
+		_ = tokio::signal::ctrl_c() => {
+			return Ok(ConnectionEnd::Interrupted);
+		}
+		r = events.next() => r,
+	};
+	drop(events);
+	match r {
+		Some(r) => {
+			r?;
+		}
+		None => return Ok(ConnectionEnd::Lost),
+	}
+	*connected = true;
+	info!("Connected");
+
+	let mut scripts = Scripts::new();
+	// Responses for the old connection are not needed anymore
+	bot.queue.clear();
+	bot.delayed.clear();
+	match event_loop(bot, &mut con, watcher, &mut scripts).await? {
+		ConnectionEnd::Interrupted => {}
+		end => return Ok(end),
+	}
+
+	// Disconnect
+	con.disconnect(
+		DisconnectOptions::new()
+			.reason(Reason::Clientdisconnect)
+			.message(bot.settings.disconnect_message.clone()),
+	)?;
+	con.events().for_each(|_| future::ready(())).await;
+
+	Ok(ConnectionEnd::Quit)
+}
+
+/// Handle events, timers, running scripts, queued responses and changed files
+/// until the events of `source` end or ctrl + c is pressed.
+async fn event_loop(
+	bot: &mut Bot,
+	source: &mut dyn EventSource,
+	watcher: &mut Option<FileWatcher>,
+	scripts: &mut Scripts,
+) -> Result<ConnectionEnd> {
+	loop {
+		let next_timer = bot.timers.iter().map(|t| t.next).min();
+		let next_queued = next_queued(bot);
+		let next_delayed = bot.next_delayed();
+		tokio::select! {
+			// Wait for ctrl + c
+			_ = tokio::signal::ctrl_c() => {
+				return Ok(ConnectionEnd::Interrupted);
+			}
+			// Run timers
+			_ = tokio::time::sleep_until(
+				next_timer.unwrap_or_else(Instant::now).into()
+			), if next_timer.is_some() => {
+				run_timers(bot, source.connection(), scripts);
+			}
+			// Send queued responses
+			_ = tokio::time::sleep_until(
+				next_queued.unwrap_or_else(Instant::now).into()
+			), if next_queued.is_some() => {
+				send_queued(bot, source.connection());
+			}
+			// Perform delayed operations
+			_ = tokio::time::sleep_until(
+				next_delayed.unwrap_or_else(Instant::now).into()
+			), if next_delayed.is_some() => {
+				run_delayed(bot, source.connection(), scripts);
+			}
+			// Wait for running scripts
+			Some(res) = scripts.next(), if !scripts.is_empty() => {
+				handle_script_result(bot, source.connection(), scripts, res);
+			}
+			// Reload when files change
+			_ = watch::changed(watcher) => watch::reload(watcher, bot),
+			// Listen to events
+			e = source.next_events() => match e {
+				Some(Ok(events)) => {
+					let con = source.connection();
+					for e in &events {
+						handle_event(bot, con, scripts, e);
+					}
+					check_reload(bot);
+					watch::update(watcher, bot);
+				}
+				// Disconnecting with `.quit` can end with an error
+				Some(Err(error)) if bot.should_quit.get() => {
+					debug!(%error, "Connection error after quitting");
+					return Ok(ConnectionEnd::Quit);
+				}
+				Some(Err(error)) => return Err(error),
+				None if bot.should_quit.get() => {
+					return Ok(ConnectionEnd::Quit);
+				}
+				None => return Ok(ConnectionEnd::Lost),
+			}
+		}
+	}
+}
+
+/// Wait for all running scripts and queued responses, then reload the
+/// settings if that was requested.
+///
+/// Delayed operations are only performed if they are already due.
+async fn finish(
+	bot: &mut Bot,
+	con: &mut dyn BotConnection,
+	scripts: &mut Scripts,
+) {
+	// Wait for all scripts, so responses are sent in order
+	loop {
+		while let Some(res) = scripts.next().await {
+			handle_script_result(bot, con, scripts, res);
+		}
+		run_delayed(bot, con, scripts);
+		if scripts.is_empty() {
+			break;
+		}
+	}
+	// Wait until queued responses are sent
+	while let Some(next) = next_queued(bot) {
+		tokio::time::sleep_until(next.into()).await;
+		send_queued(bot, con);
+	}
+
+	check_reload(bot);
+}
+
+impl EventSource for Connection {
+	fn connection(&mut self) -> &mut dyn BotConnection { self }
+
+	fn next_events(&mut self) -> EventsFuture<'_> {
+		Box::pin(async move {
+			loop {
+				let item = self.events().next().await;
+				match item? {
+					Ok(StreamItem::BookEvents(e)) => {
+						return Some(Ok(convert_events(self, &e)));
+					}
+					Ok(_) => {}
+					Err(error) => return Some(Err(error.into())),
+				}
+			}
+		})
+	}
+}
+
+impl<S: Stream<Item = BotEvent> + Unpin> EventSource for StreamSource<'_, S> {
+	fn connection(&mut self) -> &mut dyn BotConnection { self.con }
+
+	fn next_events(&mut self) -> EventsFuture<'_> {
+		Box::pin(self.events.next().map(|e| e.map(|e| Ok(vec![e]))))
+	}
+}
+
+/// Reload the settings if it was requested.
+fn check_reload(bot: &mut Bot) {
+	if bot.should_reload.get() {
+		bot.should_reload.set(false);
+		match load_settings(bot) {
+			Ok(()) => info!("Reloaded successfully"),
+			Err(error) => error!(%error, "Failed to reload"),
+		}
+	}
+}
+
+/// Load the settings and all actions.
+///
+/// If something fails to load, the bot keeps its previous settings and
+/// actions. When the bot starts, broken action files are only logged, so it
+/// can start and reload once they are fixed.
+fn load_settings(bot: &mut Bot) -> Result<()> {
+	let mut files = Vec::new();
+	// Reload settings
+	let settings = match &bot.settings_path {
+		Some(path) => {
+			files.push(path.clone());
+			match fs::read_to_string(path) {
+				Ok(r) => match toml::from_str(&r) {
+					Ok(s) => s,
+					Err(e) => bail!("Failed to parse settings: {}", e),
+				},
+				Err(error) => {
+					// Only a soft error
+					warn!(%error, "Failed to read settings, using defaults");
+					bot.settings.clone()
+				}
+			}
+		}
+		None => bot.settings.clone(),
+	};
+	if let Err(e) = Limit::global(&settings).validate() {
+		bail!("Invalid rate_limit: {}", e);
+	}
+	for (name, limit) in [
+		("user_rate_limit", &settings.user_rate_limit),
+		("target_rate_limit", &settings.target_rate_limit),
+	] {
+		if let Some(Err(e)) = limit.as_ref().map(Limit::validate) {
+			bail!("Invalid {}: {}", name, e);
+		}
+	}
+
+	// Reload actions
+	let mut actions = ActionList::default();
+	let mut events = EventActions::default();
+	let mut timers = Vec::new();
+	for f in [&settings.actions, &bot.extra_actions] {
+		if let Err(e) = load_actions(
+			&bot.base_dir,
+			&mut actions,
+			&mut events,
+			&mut timers,
+			&mut files,
+			f,
+		) {
+			if bot.generation != 0 {
+				bail!("Failed to load actions: {}", e);
+			}
+			// Start anyway, the file is watched and can be fixed later
+			error!(error = %e, "Failed to load actions");
+		}
+	}
+
+	// Dynamic actions
+	let path = Path::new(&settings.dynamic_actions);
+	let path = if path.is_absolute() {
+		path.into()
+	} else {
+		bot.base_dir.join(path)
+	};
+	files.push(path.clone());
+	let mut dynamic: ActionFile = match fs::read_to_string(&path) {
+		Ok(s) => match toml::from_str(&s) {
+			Ok(r) => r,
+			Err(e) if bot.generation != 0 => {
+				bail!("Failed to parse dynamic actions: {}", e)
+			}
+			Err(error) => {
+				// Start anyway, the file is watched and can be fixed later
+				error!(%error, "Failed to parse dynamic actions");
+				ActionFile::default()
+			}
+		},
+		Err(error) => {
+			debug!(%error, "Dynamic actions not loaded");
+			ActionFile::default()
+		}
+	};
+	// Responses which were added before placeholders existed can contain
+	// unescaped braces
+	let mut escaped = false;
+	for a in &mut dynamic.on_message {
+		if let Some(r) = &mut a.response {
+			escaped |= r.escape_old_responses(a.matcher.regex.as_deref());
+		}
+	}
+	let mut dynamic_actions = ActionList::default();
+	if let Err(e) = load_actions(
+		&bot.base_dir,
+		&mut dynamic_actions,
+		&mut events,
+		&mut timers,
+		&mut files,
+		&dynamic,
+	) {
+		if bot.generation != 0 {
+			bail!("Failed to load dynamic actions: {}", e);
+		}
+		// Start anyway, the file is watched and can be fixed later
+		error!(error = %e, "Failed to load dynamic actions");
+		// Do not save actions which could not be loaded
+		escaped = false;
+	}
+
+	// Everything was loaded successfully
+	if escaped {
+		warn!(file = ?path, "Escaped braces in old dynamic actions");
+		match toml::to_string(&dynamic) {
+			Ok(s) => {
+				if let Err(error) = fs::write(&path, s) {
+					error!(%error, "Failed to save dynamic actions");
+				}
+			}
+			Err(error) => error!(%error, "Failed to save dynamic actions"),
+		}
+	}
+	bot.settings = settings;
+	bot.actions = actions;
+	bot.events = events;
+	timer::keep_next(&mut timers, &bot.timers);
+	bot.timers = timers;
+	// Also after writing the dynamic actions, so the bot ignores its own
+	// changes
+	bot.file_versions = files.iter().map(|f| watch::file_version(f)).collect();
+	bot.files = files;
+	bot.generation += 1;
+	// Load plugin commands here, otherwise .del will never trigger
+	plugin::add_commands(bot);
+	bot.actions.0.extend(dynamic_actions.0);
+
+	// Reload the values of scripts, they are saved after every change
+	let path = bot.base_dir.join(&bot.settings.script_store);
+	match Store::load(path) {
+		Ok(store) => *bot.store.lock().unwrap() = store,
+		Err(error) => error!(%error, "Failed to load the script store"),
+	}
+
+	plugin::reload(bot);
+	debug!(actions = ?bot.actions, events = ?bot.events, "Loaded actions");
+	Ok(())
+}
+
+/// Load the actions from `f` and its includes.
+///
+/// The paths of included files are added to `files`.
+fn load_actions(
+	base: &Path,
+	actions: &mut ActionList,
+	events: &mut EventActions,
+	timers: &mut Vec<Timer>,
+	files: &mut Vec<PathBuf>,
+	f: &ActionFile,
+) -> Result<()> {
+	for a in &f.on_message {
+		actions.0.push(a.to_action_in(base)?);
+	}
+	for (defs, list) in [
+		(&f.on_join, &mut events.join),
+		(&f.on_leave, &mut events.leave),
+		(&f.on_channel_enter, &mut events.channel_enter),
+		(&f.on_channel_leave, &mut events.channel_leave),
+	] {
+		for a in defs {
+			list.0.push(a.to_action_in(base)?);
+		}
+	}
+	for t in &f.on_timer {
+		timers.push(t.to_timer()?);
+	}
+	// Handle includes
+	for i in &f.include {
+		let path = base.join(i);
+		files.push(path.clone());
+		let f2: ActionFile = toml::from_str(&fs::read_to_string(&path)?)?;
+		load_actions(
+			path.parent().unwrap_or(base),
+			actions,
+			events,
+			timers,
+			files,
+			&f2,
+		)?;
+	}
+
+	Ok(())
+}
+
+/// Get the events which the bot handles from the events of a server
+/// connection.
+fn convert_events(con: &Connection, events: &[Event]) -> Vec<BotEvent> {
+	let mut res = Vec::new();
+	for e in events {
+		match e {
+			Event::Message {
+				target,
+				invoker,
+				message,
+			} => res.push(BotEvent::Message {
+				target: *target,
+				invoker: invoker.clone(),
+				message: message.clone(),
+			}),
+			Event::PropertyAdded {
+				id: PropertyId::Client(client),
+				..
+			} => {
+				let client = match con.client(*client) {
+					Some(r) => r,
+					None => continue,
+				};
+				let enter = Some(client.channel) == own_channel(con);
+				res.push(BotEvent::Join(client.clone()));
+				if enter {
+					res.push(BotEvent::ChannelEnter(client));
+				}
+			}
+			Event::PropertyRemoved {
+				id: PropertyId::Client(_),
+				old: PropertyValue::Client(client),
+				..
+			} => {
+				let client = ClientInfo::from(client);
+				if Some(client.channel) == own_channel(con) {
+					res.push(BotEvent::ChannelLeave(client.clone()));
+				}
+				res.push(BotEvent::Leave(client));
+			}
+			Event::PropertyChanged {
+				id: PropertyId::ClientChannel(client),
+				old: PropertyValue::ChannelId(old_channel),
+				..
+			} => {
+				let client = match con.client(*client) {
+					Some(r) => r,
+					None => continue,
+				};
+				let own_channel = own_channel(con);
+				if Some(*old_channel) == own_channel {
+					res.push(BotEvent::ChannelLeave(client));
+				} else if Some(client.channel) == own_channel {
+					res.push(BotEvent::ChannelEnter(client));
+				}
+			}
+			_ => {}
+		}
+	}
+	res
+}
+
+fn handle_event(
+	bot: &mut Bot,
+	con: &mut dyn BotConnection,
+	scripts: &mut Scripts,
+	event: &BotEvent,
+) {
+	let (trigger, client) = match event {
+		BotEvent::Message {
+			target,
+			invoker,
+			message,
+		} => {
+			let msg = Message {
+				target: *target,
+				invoker: invoker.as_ref(),
+				message,
+			};
+			handle_chat_message(bot, con, scripts, &msg);
+			return;
+		}
+		BotEvent::Join(client) => (Trigger::Join, client),
+		BotEvent::Leave(client) => (Trigger::Leave, client),
+		BotEvent::ChannelEnter(client) => (Trigger::ChannelEnter, client),
+		BotEvent::ChannelLeave(client) => (Trigger::ChannelLeave, client),
+	};
+	handle_client_event(bot, con, scripts, trigger, client);
+}
+
+/// Handle a chat message unless it is from the bot itself or rate limited.
+fn handle_chat_message(
+	bot: &mut Bot,
+	con: &mut dyn BotConnection,
+	scripts: &mut Scripts,
+	msg: &Message,
+) {
+	// Ignore messages from ourself
+	if con.own_client().ok() == Some(msg.invoker.id) {
+		return;
+	}
+	if let Some(limited) = is_rate_limited(bot, con, Trigger::Message, msg) {
+		warn!(
+			target = ?msg.target,
+			invoker = ?msg.invoker,
+			?limited,
+			message = msg.message,
+			"Ignored message because of rate limiting"
+		);
+		return;
+	}
+
+	debug!(
+		target = ?msg.target,
+		invoker = ?msg.invoker,
+		message = msg.message,
+		"Got message"
+	);
+
+	handle_message(bot, con, scripts, Trigger::Message, msg, 0);
+}
+
+/// The channel where the bot currently is.
+fn own_channel(con: &dyn BotConnection) -> Option<ChannelId> {
+	con.client(con.own_client().ok()?).map(|c| c.channel)
+}
+
+/// Handle a client joining, leaving or changing its channel.
+///
+/// The `contains` and `regex` matchers check the name of the client.
+/// Responses for join and leave are sent to the server chat, responses for
+/// channel changes to the channel chat.
+fn handle_client_event(
+	bot: &mut Bot,
+	con: &mut dyn BotConnection,
+	scripts: &mut Scripts,
+	trigger: Trigger,
+	client: &ClientInfo,
+) {
+	// Ignore ourself
+	if con.own_client().ok() == Some(client.id) {
+		return;
+	}
+	plugin::on_event(bot, con, trigger, client);
+	if bot.events.get(trigger).0.is_empty() {
+		return;
+	}
+	let target = match trigger {
+		Trigger::ChannelEnter | Trigger::ChannelLeave => MessageTarget::Channel,
+		_ => MessageTarget::Server,
+	};
+	let msg = Message {
+		target,
+		invoker: InvokerRef {
+			name: &client.name,
+			id: client.id,
+			uid: client.uid.as_deref(),
+		},
+		message: &client.name,
+	};
+	// Match the server groups of clients which left against the event
+	let con = &mut EventConnection { con, client };
+	if let Some(limited) = is_rate_limited(bot, con, trigger, &msg) {
+		warn!(
+			?trigger,
+			?limited,
+			name = client.name.as_str(),
+			"Ignored event because of rate limiting"
+		);
+		return;
+	}
+	debug!(?trigger, name = client.name.as_str(), "Got client event");
+	handle_message(bot, con, scripts, trigger, &msg, 0);
+}
+
+/// Check if an incoming message should be ignored because of rate limiting.
+///
+/// If responses are queued, only the user limit is checked. Users only get a
+/// strike for hitting their limit if an action would have answered.
+fn is_rate_limited(
+	bot: &mut Bot,
+	con: &dyn BotConnection,
+	trigger: Trigger,
+	msg: &Message,
+) -> Option<Limited> {
+	match bot
+		.rate_limiter
+		.check(&bot.settings, Some(&msg.invoker), msg.target)
+	{
+		Some(Limited::Global | Limited::Target)
+			if bot.settings.rate_limit_policy != QueuePolicy::Drop =>
+		{
+			None
+		}
+		Some(Limited::User) => {
+			if trigger_actions(bot, trigger).matches(con, msg)
+				&& bot.rate_limiter.strike(&bot.settings, &msg.invoker)
+			{
+				Some(Limited::Ignored)
+			} else {
+				Some(Limited::User)
+			}
+		}
+		r => r,
+	}
+}
+
+/// The actions for messages or for an event.
+fn trigger_actions(bot: &Bot, trigger: Trigger) -> &ActionList {
+	match trigger {
+		Trigger::Message => &bot.actions,
+		_ => bot.events.get(trigger),
+	}
+}
+
+/// Search a reaction for a message, starting at the action with index
+/// `start`.
+fn handle_message(
+	bot: &mut Bot,
+	con: &mut dyn BotConnection,
+	scripts: &mut Scripts,
+	trigger: Trigger,
+	msg: &Message,
+	start: usize,
+) {
+	match trigger_actions(bot, trigger).handle(bot, con, msg, start) {
+		Handled::Nothing => {}
+		Handled::Response(response) => {
+			let response = response.into_owned();
+			send_response(bot, con, Some(&msg.invoker), msg.target, &response);
+		}
+		// Events are not answered, so the client is not told about it
+		Handled::NotAllowed if trigger != Trigger::Message => {}
+		Handled::NotAllowed => {
+			let response = bot.settings.not_allowed.clone();
+			send_response(bot, con, Some(&msg.invoker), msg.target, &response);
+		}
+		Handled::Pending(action, output) => {
+			push_script(bot, scripts, trigger, msg, action, output);
+		}
+	}
+}
+
+/// Wait for the output of a script in the background.
+fn push_script(
+	bot: &Bot,
+	scripts: &mut Scripts,
+	trigger: Trigger,
+	msg: &Message,
+	action: usize,
+	output: ScriptFuture,
+) {
+	let message = OwnedMessage::from(msg);
+	let generation = bot.generation;
+	scripts.push(Box::pin(async move {
+		ScriptResult {
+			message,
+			action,
+			generation,
+			output: output.await,
+			trigger,
+		}
+	}));
+}
+
+/// Execute all timers which are due.
+fn run_timers(
+	bot: &mut Bot,
+	con: &mut dyn BotConnection,
+	scripts: &mut Scripts,
+) {
+	let now = Instant::now();
+	// The bot itself is the invoker of timers
+	let own_client = con.own_client().ok().and_then(|id| con.client(id));
+
+	for i in 0..bot.timers.len() {
+		if bot.timers[i].next > now {
+			continue;
+		}
+		bot.timers[i].next = bot.timers[i].schedule.next_after(now);
+
+		let own_client = match &own_client {
+			Some(r) => r,
+			None => continue,
+		};
+		let timer = &bot.timers[i];
+		let target = match timer.target.resolve(con) {
+			Some(r) => r,
+			None => {
+				warn!(target = ?timer.target, "Timer target not found");
+				continue;
+			}
+		};
+		debug!(?target, "Running timer");
+
+		let msg = Message {
+			target,
+			invoker: InvokerRef {
+				name: &own_client.name,
+				id: own_client.id,
+				uid: own_client.uid.as_deref(),
+			},
+			message: "",
+		};
+		match timer.reaction.execute(bot, con, &msg, &Captures::new()) {
+			Some(Response::Text(response)) if !response.is_empty() => {
+				let response = response.into_owned();
+				send_response(bot, con, None, target, &response);
+			}
+			Some(Response::Script(output)) => {
+				push_script(bot, scripts, Trigger::Timer, &msg, i, output);
+			}
+			Some(Response::Text(_)) | None => {}
+		}
+	}
+}
+
+/// Perform the delayed operations which are due.
+fn run_delayed(
+	bot: &mut Bot,
+	con: &mut dyn BotConnection,
+	scripts: &mut Scripts,
+) {
+	let now = Instant::now();
+	let (mut due, later): (Vec<_>, _) = std::mem::take(&mut bot.delayed)
+		.into_iter()
+		.partition(|d| d.at <= now);
+	bot.delayed = later;
+	due.sort_by_key(|d| d.at);
+	for d in due {
+		handle_script_result(bot, con, scripts, d.result);
+	}
+}
+
+fn handle_script_result(
+	bot: &mut Bot,
+	con: &mut dyn BotConnection,
+	scripts: &mut Scripts,
+	res: ScriptResult,
+) {
+	let msg = res.message.as_message();
+	let next = match res.output {
+		Some(operations) => perform_operations(
+			bot,
+			con,
+			res.trigger,
+			&msg,
+			res.action,
+			operations,
+		),
+		None => true,
+	};
+	if res.trigger == Trigger::Timer {
+		// Timers have no cooldown and no next action
+		return;
+	}
+	if res.generation != bot.generation {
+		if next {
+			debug!(message = ?res.message, "Actions were reloaded while the \
+				script was running, not trying the next action");
+		}
+		return;
+	}
+	if !next {
+		// The script handled the message
+		if let Some(a) = trigger_actions(bot, res.trigger).0.get(res.action) {
+			a.start_cooldown(bot, &msg);
+		}
+		return;
+	}
+	// Try the next action
+	handle_message(bot, con, scripts, res.trigger, &msg, res.action + 1);
+}
+
+/// Perform the operations which a script returned for a message.
+///
+/// Returns `true` if the next action should be tried.
+fn perform_operations(
+	bot: &mut Bot,
+	con: &mut dyn BotConnection,
+	trigger: Trigger,
+	msg: &Message,
+	action: usize,
+	operations: Vec<Operation>,
+) -> bool {
+	// Timers are not triggered by a user
+	let invoker = Some(&msg.invoker).filter(|_| trigger != Trigger::Timer);
+	let mut next = false;
+	for operation in operations {
+		debug!(?operation, "Performing script operation");
+		match operation {
+			Operation::Send {
+				target,
+				client,
+				message,
+			} => {
+				let target = match target {
+					None => msg.target,
+					Some(OperationTarget::Server) => MessageTarget::Server,
+					Some(OperationTarget::Channel) => MessageTarget::Channel,
+					Some(OperationTarget::Client) => MessageTarget::Client(
+						client.map(ClientId).unwrap_or(msg.invoker.id),
+					),
+				};
+				send_response(bot, con, invoker, target, &message);
+			}
+			Operation::Poke { client, message } => {
+				let client = client.map(ClientId).unwrap_or(msg.invoker.id);
+				if con.client(client).is_none() {
+					error!(client = client.0, "Client to poke not found");
+					continue;
+				}
+				send_response(
+					bot,
+					con,
+					invoker,
+					MessageTarget::Poke(client),
+					&message,
+				);
+			}
+			Operation::Move { channel } => {
+				match connection::find_channel(con, &channel) {
+					Some(id) => {
+						if let Err(error) = con.move_to(id) {
+							error!(%error, ?channel, "Failed to move");
+						}
+					}
+					None => warn!(?channel, "Channel not found"),
+				}
+			}
+			Operation::Kick {
+				client,
+				from,
+				reason,
+			} => {
+				let from_server = from == KickFrom::Server;
+				if let Err(error) =
+					con.kick(ClientId(client), from_server, &reason)
+				{
+					error!(%error, client, "Failed to kick client");
+				}
+			}
+			Operation::Stop => return false,
+			Operation::Continue => next = true,
+			Operation::Later { delay, operations } => {
+				// Only schedule the operations, so nothing waits for them
+				let at = match Instant::now().checked_add(delay) {
+					Some(r) => r,
+					None => {
+						warn!(?delay, "Delay is too long");
+						continue;
+					}
+				};
+				bot.delayed.push(DelayedOperations {
+					at,
+					result: ScriptResult {
+						message: OwnedMessage::from(msg),
+						action,
+						generation: bot.generation,
+						output: Some(operations),
+						trigger,
+					},
+				});
+			}
+		}
+	}
+	next
+}
+
+/// Send a response or queue it if the rate limit is reached.
+///
+/// Long responses are split into multiple messages.
+///
+/// `invoker` is `None` for responses which were not triggered by a user.
+fn send_response(
+	bot: &mut Bot,
+	con: &mut dyn BotConnection,
+	invoker: Option<&InvokerRef>,
+	target: MessageTarget,
+	response: &str,
+) {
+	if let Some(invoker) = invoker {
+		bot.rate_limiter.consume_user(&bot.settings, invoker);
+	}
+	let max_len = match target {
+		MessageTarget::Poke(_) => {
+			MAX_POKE_LENGTH.min(bot.settings.max_message_length)
+		}
+		_ => bot.settings.max_message_length,
+	};
+	let parts = split::split_message(
+		response,
+		max_len,
+		bot.settings.max_message_parts.max(1),
+	);
+	if parts.len() > 1 {
+		debug!(?target, parts = parts.len(), "Split long response");
+	}
+	for part in parts {
+		send_part(bot, con, target, part);
+	}
+}
+
+/// Send a single message or queue it if the rate limit is reached.
+fn send_part(
+	bot: &mut Bot,
+	con: &mut dyn BotConnection,
+	target: MessageTarget,
+	message: String,
+) {
+	if bot.queue.is_empty()
+		&& bot.rate_limiter.next_send(&bot.settings, target) <= Instant::now()
+	{
+		bot.rate_limiter.consume_send(&bot.settings, target);
+		send_message(con, target, &message);
+		return;
+	}
+
+	if bot.queue.len() >= bot.settings.rate_limit_queue_size {
+		let old = if bot.settings.rate_limit_policy == QueuePolicy::DropOldest {
+			bot.queue.pop_front()
+		} else {
+			None
+		};
+		match old {
+			Some(old) => {
+				warn!(
+					target = ?old.target,
+					response = old.message.as_str(),
+					"Dropped oldest queued response because of rate limiting"
+				);
+			}
+			None => {
+				warn!(
+					?target,
+					response = message.as_str(),
+					"Dropped response because of rate limiting"
+				);
+				return;
+			}
+		}
+	}
+	debug!(?target, response = message.as_str(), "Queued response");
+	bot.queue.push_back(QueuedResponse { target, message });
+}
+
+/// Send queued responses as far as the rate limit allows.
+fn send_queued(bot: &mut Bot, con: &mut dyn BotConnection) {
+	while let Some(r) = bot.queue.front() {
+		let target = r.target;
+		if bot.rate_limiter.next_send(&bot.settings, target) > Instant::now() {
+			break;
+		}
+		let r = bot.queue.pop_front().unwrap();
+		bot.rate_limiter.consume_send(&bot.settings, target);
+		send_message(con, target, &r.message);
+	}
+}
+
+/// When the next queued response can be sent.
+fn next_queued(bot: &mut Bot) -> Option<Instant> {
+	let target = bot.queue.front()?.target;
+	Some(bot.rate_limiter.next_send(&bot.settings, target))
+}
+
+fn send_message(
+	con: &mut dyn BotConnection,
+	target: MessageTarget,
+	message: &str,
+) {
+	if let Err(error) = con.send_message(target, message) {
+		error!(%error, "Failed to send response")
+	}
+}
+
+fn escape_bb(s: &str) -> String { s.replace('[', "\\[") }
@@ -0,0 +1,177 @@
+use base64::{prelude::BASE64_STANDARD, Engine as _};
+use serde::{Deserialize, Serialize};
+use tsclientlib::InvokerRef;
+
+use crate::connection::BotConnection;
+use crate::Bot;
+
+/// Who is allowed to use a builtin command or action.
+///
+/// A user is allowed if the uid is in `uids` or if the user is in one of the
+/// `server_groups`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Permission {
+	/// Base64 encoded uids.
+	#[serde(default = "Vec::new")]
+	pub uids: Vec<String>,
+	/// Server group ids.
+	#[serde(default = "Vec::new")]
+	pub server_groups: Vec<u64>,
+}
+
+impl Permission {
+	pub fn allows(
+		&self,
+		con: &dyn BotConnection,
+		invoker: &InvokerRef,
+	) -> bool {
+		if let Some(uid) = &invoker.uid {
+			let uid = BASE64_STANDARD.encode(&uid.0);
+			if self.uids.contains(&uid) {
+				return true;
+			}
+		}
+
+		if self.server_groups.is_empty() {
+			return false;
+		}
+		match con.client(invoker.id) {
+			Some(client) => client
+				.server_groups
+				.iter()
+				.any(|g| self.server_groups.contains(&g.0)),
+			None => false,
+		}
+	}
+}
+
+/// Check if `invoker` may use the builtin or plugin command with this name.
+///
+/// Everything without an entry in the `permissions` settings is allowed.
+pub fn is_allowed(
+	bot: &Bot,
+	con: &dyn BotConnection,
+	name: &str,
+	invoker: &InvokerRef,
+) -> bool {
+	match bot.settings.permissions.get(name) {
+		Some(p) => p.allows(con, invoker),
+		None => true,
+	}
+}
+
+/// Check if `invoker` may use the action with this name.
+///
+/// Actions have their own namespace in the `action_permissions` settings, so
+/// an action called `quit` is not restricted by the permission of `.quit`.
+pub fn is_action_allowed(
+	bot: &Bot,
+	con: &dyn BotConnection,
+	name: &str,
+	invoker: &InvokerRef,
+) -> bool {
+	match bot.settings.action_permissions.get(name) {
+		Some(p) => p.allows(con, invoker),
+		None => true,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tsclientlib::{MessageTarget, ServerGroupId};
+
+	use super::*;
+	use crate::action::Trigger;
+	use crate::test_util::{
+		test_client, test_message, FakeConnection, TestDir,
+	};
+
+	fn restricted() -> Permission {
+		Permission {
+			uids: Vec::new(),
+			server_groups: vec![6],
+		}
+	}
+
+	#[test]
+	fn namespaces() {
+		let dir = TestDir::new("permission_namespaces");
+		let mut bot = Bot::new();
+		bot.base_dir = dir.to_path_buf();
+		bot.extra_actions = toml::from_str(
+			"[[on_message]]\nname = \"quit\"\ncontains = \"bye\"\nresponse = \
+			 \"Bye\"\n",
+		)
+		.unwrap();
+		bot.settings.permissions.insert("quit".into(), restricted());
+		crate::load_settings(&mut bot).unwrap();
+		let mut con = FakeConnection::new();
+		let mut scripts = crate::Scripts::new();
+		let msg = test_message(MessageTarget::Channel, "bye");
+
+		// The permission of the builtin does not restrict the action
+		crate::handle_message(
+			&mut bot,
+			&mut con,
+			&mut scripts,
+			Trigger::Message,
+			&msg,
+			0,
+		);
+		assert_eq!(con.sent.pop().unwrap().1, "Bye");
+
+		bot.settings
+			.action_permissions
+			.insert("quit".into(), restricted());
+		crate::handle_message(
+			&mut bot,
+			&mut con,
+			&mut scripts,
+			Trigger::Message,
+			&msg,
+			0,
+		);
+		assert_eq!(con.sent.pop().unwrap().1, bot.settings.not_allowed);
+	}
+
+	#[test]
+	fn events() {
+		let dir = TestDir::new("permission_events");
+		let mut bot = Bot::new();
+		bot.base_dir = dir.to_path_buf();
+		bot.extra_actions = toml::from_str(
+			"[[on_join]]\nname = \"greet\"\nresponse = \"Hi\"\n",
+		)
+		.unwrap();
+		bot.settings
+			.action_permissions
+			.insert("greet".into(), restricted());
+		crate::load_settings(&mut bot).unwrap();
+		let mut con = FakeConnection::new();
+		let mut scripts = crate::Scripts::new();
+		let mut client = test_client();
+		con.clients.push(client.clone());
+
+		// Events are skipped without telling the client
+		crate::handle_client_event(
+			&mut bot,
+			&mut con,
+			&mut scripts,
+			Trigger::Join,
+			&client,
+		);
+		assert!(con.sent.is_empty());
+
+		client.server_groups.push(ServerGroupId(6));
+		con.clients[1] = client.clone();
+		crate::handle_client_event(
+			&mut bot,
+			&mut con,
+			&mut scripts,
+			Trigger::Join,
+			&client,
+		);
+		assert_eq!(con.sent.pop().unwrap().1, "Hi");
+	}
+}
@@ -0,0 +1,191 @@
+//! Extend the bot with Rust code.
+//!
+//! A [`Plugin`] can add commands, react to clients joining and leaving and
+//! keep its own state. The builtin commands are a plugin too.
+
+use std::borrow::Cow;
+use std::fmt;
+
+use regex::Regex;
+
+use crate::action::{Action, Matcher, Reaction, Trigger};
+use crate::connection::{BotConnection, ClientInfo};
+use crate::{Bot, Message};
+
+/// A command of a plugin.
+#[derive(Clone, Debug)]
+pub struct Command {
+	/// The name of the command, it is passed to [`Plugin::run_command`].
+	///
+	/// If the command is `restricted`, it can be restricted to certain users
+	/// with this name in the `permissions` settings, like builtin commands.
+	pub name: String,
+	/// Messages which match this regex run the command.
+	pub regex: Regex,
+	/// If the command can be restricted in the `permissions` settings.
+	pub restricted: bool,
+}
+
+/// A plugin which extends the bot.
+///
+/// Plugins are registered once with [`Bot::add_plugin`] and stay loaded when
+/// the settings are reloaded. Plugins can keep state, methods which are
+/// called while handling messages only get `&self`, so state which changes
+/// there needs interior mutability.
+pub trait Plugin {
+	/// The name of the plugin, used for logging.
+	fn name(&self) -> &str;
+
+	/// The commands of this plugin.
+	///
+	/// This is called every time the actions are loaded. The commands are
+	/// added after the actions from the settings and before the dynamic
+	/// actions.
+	fn commands(&self, _bot: &Bot) -> Vec<Command> { Vec::new() }
+
+	/// Run the command with the name `command`.
+	///
+	/// If `None` is returned, the next action is tried. An empty response is
+	/// not sent.
+	fn run_command<'a>(
+		&self,
+		_bot: &Bot,
+		_con: &mut dyn BotConnection,
+		_command: &str,
+		_msg: &'a Message,
+	) -> Option<Cow<'a, str>> {
+		None
+	}
+
+	/// Called when a client joins or leaves the server or the channel of the
+	/// bot.
+	fn on_event(
+		&self,
+		_bot: &Bot,
+		_con: &mut dyn BotConnection,
+		_trigger: Trigger,
+		_client: &ClientInfo,
+	) {
+	}
+
+	/// Called after the settings and actions were loaded or reloaded.
+	fn on_reload(&mut self, _bot: &Bot) {}
+}
+
+impl fmt::Debug for dyn Plugin {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Plugin({})", self.name())
+	}
+}
+
+/// Add the commands of all plugins to the end of the action list.
+pub fn add_commands(bot: &mut Bot) {
+	let mut actions = Vec::new();
+	for (i, p) in bot.plugins.iter().enumerate() {
+		for c in p.commands(bot) {
+			actions.push(Action {
+				// Check permissions
+				name: c.restricted.then(|| c.name.clone()),
+				matchers: vec![Matcher::Regex(c.regex)],
+				reaction: Some(Reaction::Plugin {
+					plugin: i,
+					command: c.name,
+				}),
+				..Default::default()
+			});
+		}
+	}
+	bot.actions.0.extend(actions);
+}
+
+/// Tell all plugins that the settings were reloaded.
+pub fn reload(bot: &mut Bot) {
+	let mut plugins = std::mem::take(&mut bot.plugins);
+	for p in &mut plugins {
+		p.on_reload(bot);
+	}
+	bot.plugins = plugins;
+}
+
+/// Tell all plugins about a client event.
+pub fn on_event(
+	bot: &Bot,
+	con: &mut dyn BotConnection,
+	trigger: Trigger,
+	client: &ClientInfo,
+) {
+	for p in &bot.plugins {
+		p.on_event(bot, con, trigger, client);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::cell::Cell;
+
+	use tsclientlib::MessageTarget;
+
+	use super::*;
+	use crate::action::Handled;
+	use crate::test_util::{test_client, test_message, FakeConnection};
+
+	#[derive(Default)]
+	struct Counter {
+		actions: usize,
+		joined: Cell<usize>,
+	}
+
+	impl Plugin for Counter {
+		fn name(&self) -> &str { "counter" }
+
+		fn commands(&self, _: &Bot) -> Vec<Command> {
+			vec![Command {
+				name: "count".into(),
+				regex: Regex::new("^count$").unwrap(),
+				restricted: true,
+			}]
+		}
+
+		fn run_command<'a>(
+			&self,
+			_: &Bot,
+			_: &mut dyn BotConnection,
+			_: &str,
+			_: &'a Message,
+		) -> Option<Cow<'a, str>> {
+			Some(format!("{} {}", self.actions, self.joined.get()).into())
+		}
+
+		fn on_event(
+			&self,
+			_: &Bot,
+			_: &mut dyn BotConnection,
+			_: Trigger,
+			_: &ClientInfo,
+		) {
+			self.joined.set(self.joined.get() + 1);
+		}
+
+		fn on_reload(&mut self, bot: &Bot) {
+			self.actions = bot.actions.0.len();
+		}
+	}
+
+	#[test]
+	fn plugin() {
+		let mut bot = Bot::new();
+		bot.add_plugin(Counter::default());
+		add_commands(&mut bot);
+		reload(&mut bot);
+		let mut con = FakeConnection::new();
+		let client = test_client();
+		on_event(&bot, &mut con, Trigger::Join, &client);
+
+		let msg = test_message(MessageTarget::Channel, "count");
+		let expected = format!("{} 1", bot.actions.0.len());
+		match bot.actions.handle(&bot, &mut con, &msg, 0) {
+			Handled::Response(r) => assert_eq!(r, expected),
+			_ => panic!("Expected a response"),
+		}
+	}
+}
@@ -0,0 +1,586 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use base64::{prelude::BASE64_STANDARD, Engine as _};
+use serde::{Deserialize, Serialize};
+use tsclientlib::{InvokerRef, MessageTarget};
+
+use crate::Settings;
+
+/// The escalating ignore duration does not grow beyond this factor.
+const MAX_IGNORE_FACTOR: u32 = 64;
+/// Times further in the future than this count as never.
+const NEVER: Duration = Duration::from_secs(60 * 60 * 24 * 365);
+
+/// A token bucket limit.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limit {
+	/// How many responses can be sent per second on average, e.g. `0.2` for
+	/// one response every five seconds.
+	pub rate: f64,
+	/// How many responses can be sent at once.
+	pub burst: u32,
+}
+
+/// What happens to responses when the global or target rate limit is reached.
+#[derive(
+	Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueuePolicy {
+	/// Ignore incoming messages.
+	#[default]
+	Drop,
+	/// Queue responses, new responses are dropped if the queue is full.
+	Queue,
+	/// Queue responses, the oldest response is dropped if the queue is full.
+	DropOldest,
+}
+
+/// Why a message was not answered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limited {
+	/// The bot sent too many messages.
+	Global,
+	/// The user got too many responses.
+	User,
+	/// Too many responses were sent to this chat.
+	Target,
+	/// The user is temporarily ignored because of hitting the limit too
+	/// often.
+	Ignored,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum TargetKey {
+	Server,
+	Channel,
+	/// Private chat and pokes.
+	Client(u16),
+}
+
+#[derive(Debug)]
+struct Bucket {
+	tokens: f64,
+	last: Instant,
+}
+
+#[derive(Debug)]
+struct UserState {
+	bucket: Bucket,
+	/// How often the user hit the limit since the last ignore.
+	strikes: u32,
+	/// How often the user was ignored.
+	ignores: u32,
+	ignored_until: Option<Instant>,
+	/// How long the user was ignored the last time.
+	ignore_time: Duration,
+}
+
+/// Limits how many responses are sent globally, per user and per chat.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+	global: Option<Bucket>,
+	users: HashMap<String, UserState>,
+	targets: HashMap<TargetKey, Bucket>,
+}
+
+impl Limit {
+	/// Check that the limit allows sending at all.
+	pub fn validate(&self) -> Result<()> {
+		if !(self.rate.is_finite() && self.rate > 0.0) {
+			bail!(
+				"The rate of a rate limit must be positive, not {}",
+				self.rate
+			);
+		}
+		if self.burst == 0 {
+			bail!("The burst of a rate limit must not be zero");
+		}
+		Ok(())
+	}
+
+	pub(crate) fn global(settings: &Settings) -> Self {
+		Self {
+			rate: settings.rate_limit.into(),
+			burst: settings.rate_limit.into(),
+		}
+	}
+}
+
+impl From<MessageTarget> for TargetKey {
+	fn from(target: MessageTarget) -> Self {
+		match target {
+			MessageTarget::Server => TargetKey::Server,
+			MessageTarget::Channel => TargetKey::Channel,
+			MessageTarget::Client(c) | MessageTarget::Poke(c) => {
+				TargetKey::Client(c.0)
+			}
+		}
+	}
+}
+
+impl Bucket {
+	fn new(limit: &Limit, now: Instant) -> Self {
+		Self {
+			tokens: limit.burst.into(),
+			last: now,
+		}
+	}
+
+	fn refill(&mut self, limit: &Limit, now: Instant) {
+		let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+		self.tokens =
+			(self.tokens + elapsed * limit.rate).min(limit.burst.into());
+		self.last = now;
+	}
+
+	fn has_token(&mut self, limit: &Limit, now: Instant) -> bool {
+		self.refill(limit, now);
+		self.tokens >= 1.0
+	}
+
+	fn take(&mut self, limit: &Limit, now: Instant) {
+		self.refill(limit, now);
+		// Can get negative if responses were sent without checking first
+		self.tokens -= 1.0;
+	}
+
+	/// When the next token is available.
+	fn next_token(&mut self, limit: &Limit, now: Instant) -> Instant {
+		self.refill(limit, now);
+		if self.tokens >= 1.0 {
+			now
+		} else {
+			Duration::try_from_secs_f64((1.0 - self.tokens) / limit.rate)
+				.ok()
+				.filter(|d| *d < NEVER)
+				.and_then(|d| now.checked_add(d))
+				.unwrap_or(now + NEVER)
+		}
+	}
+
+	fn is_full(&mut self, limit: &Limit, now: Instant) -> bool {
+		self.refill(limit, now);
+		self.tokens >= limit.burst.into()
+	}
+}
+
+/// Identify a user by uid or by client id if the uid is unknown.
+pub fn user_key(invoker: &InvokerRef) -> String {
+	match invoker.uid {
+		Some(uid) => BASE64_STANDARD.encode(&uid.0),
+		None => format!("#{}", invoker.id.0),
+	}
+}
+
+impl RateLimiter {
+	/// Check if a message of `invoker` in `target` may be answered.
+	///
+	/// If the user limit was reached, [`strike`](Self::strike) should be
+	/// called when the message would have been answered.
+	pub fn check(
+		&mut self,
+		settings: &Settings,
+		invoker: Option<&InvokerRef>,
+		target: MessageTarget,
+	) -> Option<Limited> {
+		let now = Instant::now();
+		self.clean_up(settings, now);
+
+		if let (Some(invoker), Some(limit)) =
+			(invoker, &settings.user_rate_limit)
+		{
+			let user = self
+				.users
+				.entry(user_key(invoker))
+				.or_insert_with(|| UserState::new(limit, now));
+			if user.ignored_until.map(|t| t > now).unwrap_or(false) {
+				return Some(Limited::Ignored);
+			}
+			if !user.bucket.has_token(limit, now) {
+				return Some(Limited::User);
+			}
+			user.strikes = 0;
+		}
+
+		let global = Limit::global(settings);
+		if !self
+			.global
+			.get_or_insert_with(|| Bucket::new(&global, now))
+			.has_token(&global, now)
+		{
+			return Some(Limited::Global);
+		}
+
+		if let Some(limit) = &settings.target_rate_limit {
+			if !self
+				.targets
+				.entry(target.into())
+				.or_insert_with(|| Bucket::new(limit, now))
+				.has_token(limit, now)
+			{
+				return Some(Limited::Target);
+			}
+		}
+		None
+	}
+
+	/// Count a strike for `invoker`, who would have gotten a response if the
+	/// user limit was not reached.
+	///
+	/// Starts ignoring the user if there were too many strikes. Returns `true`
+	/// if the user is ignored now.
+	pub fn strike(
+		&mut self,
+		settings: &Settings,
+		invoker: &InvokerRef,
+	) -> bool {
+		let now = Instant::now();
+		let limit = match &settings.user_rate_limit {
+			Some(l) => l,
+			None => return false,
+		};
+		let user = self
+			.users
+			.entry(user_key(invoker))
+			.or_insert_with(|| UserState::new(limit, now));
+		user.strikes += 1;
+		match settings.rate_limit_ignore_after {
+			Some(after) if user.strikes >= after => {}
+			_ => return false,
+		}
+
+		let factor = 2u32.saturating_pow(user.ignores).min(MAX_IGNORE_FACTOR);
+		let duration = Duration::from_secs(settings.rate_limit_ignore_time)
+			.checked_mul(factor)
+			.map_or(NEVER, |d| d.min(NEVER));
+		user.strikes = 0;
+		user.ignores += 1;
+		user.ignored_until =
+			Some(now.checked_add(duration).unwrap_or(now + NEVER));
+		user.ignore_time = duration;
+		true
+	}
+
+	/// Count a response for the limit of `invoker`.
+	pub fn consume_user(&mut self, settings: &Settings, invoker: &InvokerRef) {
+		let now = Instant::now();
+		if let Some(limit) = &settings.user_rate_limit {
+			self.users
+				.entry(user_key(invoker))
+				.or_insert_with(|| UserState::new(limit, now))
+				.bucket
+				.take(limit, now);
+		}
+	}
+
+	/// Count a response for the global limit and the limit of `target`.
+	pub fn consume_send(&mut self, settings: &Settings, target: MessageTarget) {
+		let now = Instant::now();
+		let global = Limit::global(settings);
+		self.global
+			.get_or_insert_with(|| Bucket::new(&global, now))
+			.take(&global, now);
+		if let Some(limit) = &settings.target_rate_limit {
+			self.targets
+				.entry(target.into())
+				.or_insert_with(|| Bucket::new(limit, now))
+				.take(limit, now);
+		}
+	}
+
+	/// When a message can be sent to `target` without exceeding the global
+	/// and the target limit.
+	pub fn next_send(
+		&mut self,
+		settings: &Settings,
+		target: MessageTarget,
+	) -> Instant {
+		let now = Instant::now();
+		let global = Limit::global(settings);
+		let mut res = self
+			.global
+			.get_or_insert_with(|| Bucket::new(&global, now))
+			.next_token(&global, now);
+		if let Some(limit) = &settings.target_rate_limit {
+			res = res.max(
+				self.targets
+					.entry(target.into())
+					.or_insert_with(|| Bucket::new(limit, now))
+					.next_token(limit, now),
+			);
+		}
+		res
+	}
+
+	/// Forget everything which is back to the initial state.
+	///
+	/// Ignored users are remembered for as long as their last ignore lasted,
+	/// so the ignore time keeps growing if they continue afterwards.
+	fn clean_up(&mut self, settings: &Settings, now: Instant) {
+		match &settings.user_rate_limit {
+			Some(limit) => self.users.retain(|_, u| {
+				let remember_until = u
+					.ignored_until
+					.and_then(|t| t.checked_add(u.ignore_time))
+					.unwrap_or(now);
+				u.strikes > 0
+					|| remember_until > now
+					|| !u.bucket.is_full(limit, now)
+			}),
+			None => self.users.clear(),
+		}
+		match &settings.target_rate_limit {
+			Some(limit) => {
+				self.targets.retain(|_, b| !b.is_full(limit, now));
+			}
+			None => self.targets.clear(),
+		}
+	}
+}
+
+impl UserState {
+	fn new(limit: &Limit, now: Instant) -> Self {
+		Self {
+			bucket: Bucket::new(limit, now),
+			strikes: 0,
+			ignores: 0,
+			ignored_until: None,
+			ignore_time: Duration::ZERO,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tsclientlib::ClientId;
+
+	use super::*;
+	use crate::action::Trigger;
+	use crate::test_util::{test_message, FakeConnection, TestDir};
+	use crate::Bot;
+
+	fn invoker(id: u16) -> InvokerRef<'static> {
+		InvokerRef {
+			id: ClientId(id),
+			..test_message(MessageTarget::Channel, "").invoker
+		}
+	}
+
+	fn settings() -> Settings {
+		Settings {
+			rate_limit: 100,
+			user_rate_limit: Some(Limit {
+				rate: 0.001,
+				burst: 2,
+			}),
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn user_limit() {
+		let settings = settings();
+		let mut limiter = RateLimiter::default();
+		let alice = invoker(2);
+		let bob = invoker(3);
+		for _ in 0..2 {
+			assert_eq!(
+				limiter.check(&settings, Some(&alice), MessageTarget::Channel),
+				None
+			);
+			limiter.consume_user(&settings, &alice);
+		}
+		assert_eq!(
+			limiter.check(&settings, Some(&alice), MessageTarget::Channel),
+			Some(Limited::User)
+		);
+		// Other users are not affected
+		assert_eq!(
+			limiter.check(&settings, Some(&bob), MessageTarget::Channel),
+			None
+		);
+	}
+
+	#[test]
+	fn ignore_user() {
+		let settings = Settings {
+			rate_limit_ignore_after: Some(2),
+			..settings()
+		};
+		let mut limiter = RateLimiter::default();
+		let alice = invoker(2);
+		for _ in 0..2 {
+			limiter.consume_user(&settings, &alice);
+		}
+		// Messages without a response do not count
+		for _ in 0..3 {
+			assert_eq!(
+				limiter.check(&settings, Some(&alice), MessageTarget::Channel),
+				Some(Limited::User)
+			);
+		}
+		assert!(!limiter.strike(&settings, &alice));
+		assert!(limiter.strike(&settings, &alice));
+		assert_eq!(
+			limiter.check(&settings, Some(&alice), MessageTarget::Channel),
+			Some(Limited::Ignored)
+		);
+		let user = &limiter.users[&user_key(&alice)];
+		assert_eq!(user.ignore_time, Duration::from_secs(60));
+
+		// The ignore time does not overflow
+		let settings = Settings {
+			rate_limit_ignore_time: u64::MAX,
+			..settings
+		};
+		let bob = invoker(3);
+		for _ in 0..2 {
+			limiter.consume_user(&settings, &bob);
+		}
+		limiter.strike(&settings, &bob);
+		assert!(limiter.strike(&settings, &bob));
+		assert_eq!(limiter.users[&user_key(&bob)].ignore_time, NEVER);
+	}
+
+	#[test]
+	fn global_and_target_limit() {
+		let settings = Settings {
+			rate_limit: 1,
+			target_rate_limit: Some(Limit {
+				rate: 0.001,
+				burst: 1,
+			}),
+			..Default::default()
+		};
+		let mut limiter = RateLimiter::default();
+		limiter.consume_send(&settings, MessageTarget::Server);
+		assert_eq!(
+			limiter.check(&settings, None, MessageTarget::Channel),
+			Some(Limited::Global)
+		);
+
+		let settings = Settings {
+			rate_limit: 100,
+			..settings
+		};
+		let mut limiter = RateLimiter::default();
+		limiter.consume_send(&settings, MessageTarget::Server);
+		assert_eq!(
+			limiter.check(&settings, None, MessageTarget::Server),
+			Some(Limited::Target)
+		);
+		assert_eq!(
+			limiter.check(&settings, None, MessageTarget::Channel),
+			None
+		);
+	}
+
+	#[test]
+	fn invalid_limit() {
+		let limit = |rate, burst| Limit { rate, burst }.validate();
+		assert!(limit(0.5, 1).is_ok());
+		assert!(limit(0.0, 1).is_err());
+		assert!(limit(-1.0, 1).is_err());
+		assert!(limit(f64::NAN, 1).is_err());
+		assert!(limit(f64::INFINITY, 1).is_err());
+		assert!(limit(1.0, 0).is_err());
+
+		// The global limit is checked when loading the settings
+		let dir = TestDir::new("global_limit");
+		let mut bot = Bot::new();
+		bot.base_dir = dir.to_path_buf();
+		bot.settings.rate_limit = 0;
+		assert!(crate::load_settings(&mut bot).is_err());
+		bot.settings.rate_limit = 1;
+		assert!(crate::load_settings(&mut bot).is_ok());
+	}
+
+	fn queue_bot(policy: QueuePolicy) -> Bot {
+		let mut bot = Bot::new();
+		bot.settings.rate_limit = 1;
+		bot.settings.rate_limit_policy = policy;
+		bot.settings.rate_limit_queue_size = 2;
+		bot
+	}
+
+	fn queued(bot: &Bot) -> Vec<&str> {
+		bot.queue.iter().map(|r| r.message.as_str()).collect()
+	}
+
+	#[test]
+	fn drop_policy() {
+		let mut bot = queue_bot(QueuePolicy::Drop);
+		let con = FakeConnection::new();
+		let msg = test_message(MessageTarget::Channel, "");
+		bot.rate_limiter
+			.consume_send(&bot.settings, MessageTarget::Channel);
+		assert_eq!(
+			crate::is_rate_limited(&mut bot, &con, Trigger::Message, &msg),
+			Some(Limited::Global)
+		);
+
+		// Messages are answered later if responses are queued
+		bot.settings.rate_limit_policy = QueuePolicy::Queue;
+		assert_eq!(
+			crate::is_rate_limited(&mut bot, &con, Trigger::Message, &msg),
+			None
+		);
+	}
+
+	#[test]
+	fn queue_policies() {
+		for (policy, expected) in [
+			(QueuePolicy::Queue, ["1", "2"]),
+			(QueuePolicy::DropOldest, ["2", "3"]),
+		] {
+			let mut bot = queue_bot(policy);
+			let mut con = FakeConnection::new();
+			for m in ["0", "1", "2", "3"] {
+				crate::send_part(
+					&mut bot,
+					&mut con,
+					MessageTarget::Channel,
+					m.into(),
+				);
+			}
+			assert_eq!(con.sent, [(MessageTarget::Channel, "0".into())]);
+			// The queue does not grow beyond its size
+			assert_eq!(queued(&bot), expected, "{:?}", policy);
+
+			// Nothing can be sent before the next token is available
+			let now = Instant::now();
+			let next = crate::next_queued(&mut bot).unwrap();
+			assert!(next > now && next <= now + Duration::from_secs(1));
+			crate::send_queued(&mut bot, &mut con);
+			assert_eq!(con.sent.len(), 1);
+
+			// Send the rest faster
+			bot.settings.rate_limit = u8::MAX;
+			while let Some(next) = crate::next_queued(&mut bot) {
+				std::thread::sleep(
+					next.saturating_duration_since(Instant::now()),
+				);
+				crate::send_queued(&mut bot, &mut con);
+			}
+			let sent = con.sent.iter().map(|(_, m)| m.as_str());
+			assert!(sent.skip(1).eq(expected), "{:?}", policy);
+			assert!(bot.queue.is_empty());
+		}
+	}
+
+	#[test]
+	fn never() {
+		let limit = Limit {
+			rate: f64::MIN_POSITIVE,
+			burst: 1,
+		};
+		let now = Instant::now();
+		let mut bucket = Bucket::new(&limit, now);
+		bucket.take(&limit, now);
+		assert_eq!(bucket.next_token(&limit, now), now + NEVER);
+	}
+}
@@ -0,0 +1,390 @@
+//! Reactions which are written in the embedded [Rhai](https://rhai.rs)
+//! scripting language.
+//!
+//! Scripts run inside the bot, they cannot access files or start programs.
+//! The message is available in the variables `message`, `target`, `invoker`,
+//! `channel`, `captures` and `bot_name`, the same information as for commands
+//! and shell scripts.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{format_err, Result};
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{Dynamic, Engine, EvalAltResult, Scope, AST};
+use tracing::{debug, error, info};
+
+use crate::action::{Operation, ScriptFuture, ScriptInput};
+use crate::Bot;
+
+/// The maximum size of strings, arrays and maps in scripts.
+const MAX_SIZE: usize = 64 * 1024;
+
+/// A compiled Rhai script.
+#[derive(Clone, Debug)]
+pub struct RhaiScript {
+	path: PathBuf,
+	/// Shared with the blocking tasks which run the script.
+	ast: Arc<AST>,
+	/// If `None`, the default from the settings is used.
+	pub timeout: Option<Duration>,
+}
+
+/// Values which scripts keep across runs and restarts of the bot.
+///
+/// Every script and plugin has its own namespace, so they cannot read or
+/// overwrite the values of others.
+#[derive(Debug, Default)]
+pub struct Store {
+	path: PathBuf,
+	/// The values of every namespace.
+	values: BTreeMap<String, BTreeMap<String, serde_json::Value>>,
+	changed: bool,
+}
+
+impl RhaiScript {
+	pub fn compile(path: &Path) -> Result<Self> {
+		let mut engine = Engine::new();
+		// Scripts must not load other files
+		engine.set_module_resolver(DummyModuleResolver::new());
+		let ast = engine.compile_file(path.into()).map_err(|e| {
+			format_err!("Failed to compile script {}: {}", path.display(), e)
+		})?;
+		Ok(Self {
+			path: path.into(),
+			ast: Arc::new(ast),
+			timeout: None,
+		})
+	}
+
+	/// Run the script in a blocking task, so it does not stop the bot.
+	///
+	/// The replies are sent as one message. If the output is `None`, the next
+	/// action should be tried.
+	pub fn run(&self, bot: &Bot, input: ScriptInput) -> ScriptFuture {
+		let script = self.clone();
+		let store = bot.store.clone();
+		let namespace = store_namespace(bot, &self.path);
+		let timeout = self.timeout.unwrap_or_else(|| {
+			Duration::from_secs(bot.settings.script_timeout)
+		});
+		Box::pin(async move {
+			let path = script.path.clone();
+			let run =
+				move || script.run_blocking(store, namespace, timeout, &input);
+			match tokio::task::spawn_blocking(run).await {
+				Ok(r) => r.map(Operation::from_text),
+				Err(error) => {
+					error!(%error, script = %path.display(), "Failed to run script");
+					// Don't proceed
+					Some(Vec::new())
+				}
+			}
+		})
+	}
+
+	/// Run the script and return all replies, separated by line breaks.
+	///
+	/// If `None` is returned, the next action should be tried.
+	fn run_blocking(
+		&self,
+		store: Arc<Mutex<Store>>,
+		namespace: String,
+		timeout: Duration,
+		input: &ScriptInput,
+	) -> Option<String> {
+		let path = self.path.display().to_string();
+		let mut engine = Engine::new();
+		engine
+			.set_module_resolver(DummyModuleResolver::new())
+			.set_max_string_size(MAX_SIZE)
+			.set_max_array_size(MAX_SIZE)
+			.set_max_map_size(MAX_SIZE);
+		let start = Instant::now();
+		engine.on_progress(move |_| {
+			if start.elapsed() > timeout {
+				Some(Dynamic::UNIT)
+			} else {
+				None
+			}
+		});
+		let print_path = path.clone();
+		engine.on_print(move |s| info!(script = print_path.as_str(), "{}", s));
+		engine.on_debug(|s, _, pos| debug!(%pos, "{}", s));
+
+		let replies = Arc::new(Mutex::new(Vec::new()));
+		let r = replies.clone();
+		engine.register_fn("reply", move |s: &str| {
+			r.lock().unwrap().push(s.to_string());
+		});
+		let namespace = Arc::new(namespace);
+		let (s, ns) = (store.clone(), namespace.clone());
+		engine.register_fn("store_get", move |key: &str| {
+			s.lock().unwrap().get(&ns, key)
+		});
+		let (s, ns) = (store.clone(), namespace.clone());
+		engine.register_fn("store_set", move |key: &str, value: Dynamic| {
+			s.lock().unwrap().set(&ns, key, value)
+		});
+		let (s, ns) = (store.clone(), namespace);
+		engine.register_fn("store_remove", move |key: &str| {
+			s.lock().unwrap().remove(&ns, key);
+		});
+
+		let mut scope = Scope::new();
+		match rhai::serde::to_dynamic(input) {
+			Ok(input) => {
+				for (name, value) in input.cast::<rhai::Map>() {
+					scope.push_constant_dynamic(name, value);
+				}
+			}
+			Err(error) => {
+				error!(%error, script = path.as_str(), "Failed to convert script input");
+				return Some(String::new());
+			}
+		}
+
+		let result =
+			engine.eval_ast_with_scope::<Dynamic>(&mut scope, &self.ast);
+		if let Err(error) = store.lock().unwrap().save() {
+			error!(%error, "Failed to save the script store");
+		}
+		match result {
+			// Try the next action
+			Ok(r) if r.as_bool() == Ok(false) => None,
+			Ok(_) => Some(replies.lock().unwrap().join("\n")),
+			Err(error) => {
+				if let EvalAltResult::ErrorTerminated(..) = *error {
+					error!(
+						script = path.as_str(),
+						?timeout,
+						"Stopped script because it took too long"
+					);
+				} else {
+					error!(%error, script = path.as_str(), "Failed to run script");
+				}
+				// Don't proceed
+				Some(String::new())
+			}
+		}
+	}
+}
+
+/// The namespace in the store of the script or plugin at `path`.
+///
+/// Paths in the directory of the settings are relative, so the values are
+/// kept when the bot is moved.
+pub fn store_namespace(bot: &Bot, path: &Path) -> String {
+	path.strip_prefix(&bot.base_dir)
+		.unwrap_or(path)
+		.display()
+		.to_string()
+}
+
+impl Store {
+	/// Load the values from a file, a missing file is an empty store.
+	pub fn load(path: PathBuf) -> Result<Self> {
+		let values = match fs::read_to_string(&path) {
+			Ok(s) => serde_json::from_str(&s).map_err(|e| {
+				format_err!("Failed to parse {}: {}", path.display(), e)
+			})?,
+			Err(error) => {
+				debug!(%error, "Script store not loaded");
+				BTreeMap::new()
+			}
+		};
+		Ok(Self {
+			path,
+			values,
+			changed: false,
+		})
+	}
+
+	/// Write the values to the file if they were changed.
+	pub fn save(&mut self) -> Result<()> {
+		if self.changed {
+			fs::write(&self.path, serde_json::to_string_pretty(&self.values)?)?;
+			self.changed = false;
+		}
+		Ok(())
+	}
+
+	fn get_value(
+		&self,
+		namespace: &str,
+		key: &str,
+	) -> Option<&serde_json::Value> {
+		self.values.get(namespace)?.get(key)
+	}
+
+	fn set_value(
+		&mut self,
+		namespace: &str,
+		key: &str,
+		value: serde_json::Value,
+	) {
+		self.values
+			.entry(namespace.into())
+			.or_default()
+			.insert(key.into(), value);
+		self.changed = true;
+	}
+
+	/// Get a value or `()` if it does not exist.
+	fn get(&self, namespace: &str, key: &str) -> Dynamic {
+		self.get_value(namespace, key)
+			.and_then(|v| rhai::serde::to_dynamic(v).ok())
+			.unwrap_or(Dynamic::UNIT)
+	}
+
+	fn set(
+		&mut self,
+		namespace: &str,
+		key: &str,
+		value: Dynamic,
+	) -> Result<(), Box<EvalAltResult>> {
+		let value = rhai::serde::from_dynamic(&value)?;
+		self.set_value(namespace, key, value);
+		Ok(())
+	}
+
+	/// Get a value as string, other values are returned as JSON.
+	pub fn get_str(&self, namespace: &str, key: &str) -> Option<String> {
+		match self.get_value(namespace, key)? {
+			serde_json::Value::String(s) => Some(s.clone()),
+			v => Some(v.to_string()),
+		}
+	}
+
+	pub fn set_str(&mut self, namespace: &str, key: &str, value: String) {
+		self.set_value(namespace, key, value.into());
+	}
+
+	pub fn remove(&mut self, namespace: &str, key: &str) {
+		let Some(values) = self.values.get_mut(namespace) else {
+			return;
+		};
+		if values.remove(key).is_some() {
+			if values.is_empty() {
+				self.values.remove(namespace);
+			}
+			self.changed = true;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tsclientlib::MessageTarget;
+
+	use super::*;
+	use crate::template::Captures;
+	use crate::test_util::{test_message, FakeConnection, TestDir};
+
+	fn compile(source: &str) -> RhaiScript {
+		let dir = TestDir::new("script");
+		let path = dir.join("test.rhai");
+		fs::write(&path, source).unwrap();
+		RhaiScript::compile(&path).unwrap()
+	}
+
+	async fn run(
+		script: &RhaiScript,
+		bot: &Bot,
+		message: &str,
+	) -> Option<String> {
+		let con = FakeConnection::new();
+		let msg = test_message(MessageTarget::Channel, message);
+		let input = ScriptInput::new(bot, &con, &msg, &Captures::new());
+		let operations = script.run(bot, input).await?;
+		let mut replies = Vec::new();
+		for o in operations {
+			match o {
+				Operation::Send { message, .. } => replies.push(message),
+				_ => panic!("Unexpected operation {:?}", o),
+			}
+		}
+		Some(replies.join("\n"))
+	}
+
+	#[tokio::test]
+	async fn reply() {
+		let bot = Bot::new();
+		let script = compile(
+			r#"
+			reply("Hi " + invoker.name);
+			reply(`You said ${message} in the ${target} chat`);
+			"#,
+		);
+		assert_eq!(
+			run(&script, &bot, "hello").await.as_deref(),
+			Some("Hi Alice\nYou said hello in the channel chat")
+		);
+
+		// Try the next action
+		let script = compile("message != \"skip\"");
+		assert_eq!(run(&script, &bot, "skip").await, None);
+		assert_eq!(run(&script, &bot, "other").await.as_deref(), Some(""));
+	}
+
+	#[tokio::test]
+	async fn store() {
+		let bot = Bot::new();
+		let dir = TestDir::new("store");
+		let path = dir.join("store.json");
+		*bot.store.lock().unwrap() = Store::load(path.clone()).unwrap();
+
+		let script = compile(
+			r#"
+			let count = store_get("count");
+			if count == () { count = 0; }
+			store_set("count", count + 1);
+			reply(`${count + 1}`);
+			"#,
+		);
+		assert_eq!(run(&script, &bot, "a").await.as_deref(), Some("1"));
+		assert_eq!(run(&script, &bot, "a").await.as_deref(), Some("2"));
+
+		// Values are persisted
+		let store = Store::load(path.clone()).unwrap();
+		let namespace = store_namespace(&bot, &script.path);
+		assert_eq!(store.values[&namespace]["count"], serde_json::json!(2));
+
+		// Other scripts do not see the values
+		let other =
+			compile("if store_get(\"count\") == () { reply(\"none\"); }");
+		assert_eq!(run(&other, &bot, "a").await.as_deref(), Some("none"));
+	}
+
+	#[test]
+	fn namespace() {
+		let mut bot = Bot::new();
+		bot.base_dir = PathBuf::from("/bot");
+		let path = Path::new("/bot/scripts/count.rhai");
+		assert_eq!(store_namespace(&bot, path), "scripts/count.rhai");
+		let path = Path::new("/other/count.rhai");
+		assert_eq!(store_namespace(&bot, path), "/other/count.rhai");
+	}
+
+	#[tokio::test]
+	async fn no_import() {
+		let bot = Bot::new();
+		let dir = TestDir::new("import");
+		fs::write(dir.join("other.rhai"), "fn hi() { \"imported\" }").unwrap();
+		let path = dir.join("test.rhai");
+		fs::write(&path, "import \"other\" as o; reply(o::hi());").unwrap();
+		let script = RhaiScript::compile(&path).unwrap();
+		assert_eq!(run(&script, &bot, "a").await.as_deref(), Some(""));
+	}
+
+	#[tokio::test]
+	async fn timeout() {
+		let bot = Bot::new();
+		let mut script = compile("loop {}");
+		script.timeout = Some(Duration::from_millis(10));
+		assert_eq!(run(&script, &bot, "a").await.as_deref(), Some(""));
+	}
+}
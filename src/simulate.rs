@@ -0,0 +1,196 @@
+use std::time::Instant;
+
+use anyhow::{bail, Result};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tsclientlib::{ChannelId, ClientId, InvokerRef, MessageTarget, UidBuf};
+
+use crate::action::Reaction;
+use crate::connection::{BotConnection, ChannelInfo, ClientInfo};
+use crate::watch::{self, FileWatcher};
+use crate::{Bot, Message};
+
+/// A connection which prints messages instead of sending them to a server.
+///
+/// Clients are created when they send their first message, the uid of a
+/// client is its name. There is only one channel, where all clients are.
+#[derive(Debug)]
+pub struct SimulatedConnection {
+	/// The first client is the bot.
+	clients: Vec<ClientInfo>,
+	disconnected: bool,
+}
+
+impl SimulatedConnection {
+	pub fn new(name: &str) -> Self {
+		let mut res = Self {
+			clients: Vec::new(),
+			disconnected: false,
+		};
+		res.get_or_add_client(name);
+		res
+	}
+
+	/// Get a client by name or add it if it does not exist.
+	pub fn get_or_add_client(&mut self, name: &str) -> ClientInfo {
+		if let Some(c) = self.clients.iter().find(|c| c.name == name) {
+			return c.clone();
+		}
+		let client = ClientInfo {
+			id: ClientId(self.clients.len() as u16 + 1),
+			name: name.into(),
+			uid: Some(UidBuf(name.as_bytes().to_vec())),
+			channel: ChannelId(1),
+			server_groups: Vec::new(),
+		};
+		self.clients.push(client.clone());
+		client
+	}
+}
+
+impl BotConnection for SimulatedConnection {
+	fn own_client(&self) -> Result<ClientId> { Ok(self.clients[0].id) }
+
+	fn client(&self, id: ClientId) -> Option<ClientInfo> {
+		self.clients.iter().find(|c| c.id == id).cloned()
+	}
+
+	fn clients(&self) -> Vec<ClientInfo> { self.clients.clone() }
+
+	fn channel(&self, id: ChannelId) -> Option<ChannelInfo> {
+		if id == ChannelId(1) {
+			Some(ChannelInfo {
+				id,
+				name: "Simulation".into(),
+				parent: ChannelId(0),
+			})
+		} else {
+			None
+		}
+	}
+
+	fn channels(&self) -> Vec<ChannelInfo> {
+		self.channel(ChannelId(1)).into_iter().collect()
+	}
+
+	fn send_message(
+		&mut self,
+		target: MessageTarget,
+		message: &str,
+	) -> Result<()> {
+		if self.disconnected {
+			bail!("Not connected");
+		}
+		println!("{}: {}", Reaction::get_target(&target), message);
+		Ok(())
+	}
+
+	fn move_to(&mut self, channel: ChannelId) -> Result<()> {
+		let channel = match self.channel(channel) {
+			Some(c) => c,
+			None => bail!("Channel {} not found", channel.0),
+		};
+		println!("move: {}", channel.name);
+		Ok(())
+	}
+
+	fn kick(
+		&mut self,
+		client: ClientId,
+		from_server: bool,
+		reason: &str,
+	) -> Result<()> {
+		let client = match self.client(client) {
+			Some(c) => c,
+			None => bail!("Client {} not found", client.0),
+		};
+		let from = if from_server { "server" } else { "channel" };
+		println!("kick from {}: {} ({})", from, client.name, reason);
+		Ok(())
+	}
+
+	fn disconnect(&mut self) -> Result<()> {
+		self.disconnected = true;
+		Ok(())
+	}
+}
+
+/// Read messages from stdin, run them through the actions and print the
+/// responses.
+///
+/// Every line has the format `<chat> <name> <message>`, where chat is either
+/// `server`, `channel`, `client` or `poke`. Empty lines and lines starting
+/// with `#` are ignored.
+///
+/// The settings are reloaded when a file of `watcher` changes. Delayed
+/// operations are performed while waiting for the next line.
+pub async fn run(
+	bot: &mut Bot,
+	watcher: &mut Option<FileWatcher>,
+) -> Result<()> {
+	let mut con = SimulatedConnection::new(&bot.settings.name);
+	let own_client = con.own_client()?;
+	let mut lines = BufReader::new(tokio::io::stdin()).lines();
+	loop {
+		let next_delayed = bot.next_delayed();
+		let line = tokio::select! {
+			line = lines.next_line() => match line? {
+				Some(r) => r,
+				None => break,
+			},
+			_ = watch::changed(watcher) => {
+				watch::reload(watcher, bot);
+				continue;
+			}
+			_ = tokio::time::sleep_until(
+				next_delayed.unwrap_or_else(Instant::now).into()
+			), if next_delayed.is_some() => {
+				bot.process_delayed(&mut con).await;
+				if con.disconnected {
+					break;
+				}
+				continue;
+			}
+		};
+		let line = line.trim();
+		if line.is_empty() || line.starts_with('#') {
+			continue;
+		}
+
+		let mut split = line.splitn(3, ' ');
+		let (chat, name, message) =
+			match (split.next(), split.next(), split.next()) {
+				(Some(c), Some(n), Some(m)) => (c, n, m),
+				_ => {
+					eprintln!("Usage: <chat> <name> <message>");
+					continue;
+				}
+			};
+		let target = match chat {
+			"server" => MessageTarget::Server,
+			"channel" => MessageTarget::Channel,
+			"client" => MessageTarget::Client(own_client),
+			"poke" => MessageTarget::Poke(own_client),
+			_ => {
+				eprintln!("Chat must be server, channel, client or poke");
+				continue;
+			}
+		};
+
+		let client = con.get_or_add_client(name);
+		let msg = Message {
+			target,
+			invoker: InvokerRef {
+				name: &client.name,
+				id: client.id,
+				uid: client.uid.as_deref(),
+			},
+			message,
+		};
+		bot.process_message(&mut con, &msg).await;
+		watch::update(watcher, bot);
+		if con.disconnected {
+			break;
+		}
+	}
+	Ok(())
+}
@@ -0,0 +1,304 @@
+//! Split long messages into multiple parts.
+//!
+//! Messages are split at line breaks if possible, otherwise at spaces. BBCode
+//! tags are never split, tags which are open at the end of a part are closed
+//! and opened again in the next part.
+
+/// Appended to the last part if the message was truncated.
+const ELLIPSIS: &str = "…";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Kind {
+	Text,
+	/// An opening tag with its lowercase name.
+	Open(String),
+	/// A closing tag with its lowercase name.
+	Close(String),
+}
+
+/// A part of a message which is never split.
+#[derive(Clone, Debug)]
+struct Atom<'a> {
+	text: &'a str,
+	kind: Kind,
+}
+
+/// A tag which is open at some point.
+#[derive(Clone, Debug)]
+struct OpenTag<'a> {
+	/// The whole tag, e.g. `[URL=…]`.
+	text: &'a str,
+	name: String,
+}
+
+/// Where a part ends.
+struct PartEnd<'a> {
+	/// The atom after the last atom of this part.
+	end: usize,
+	/// The first atom of the next part.
+	next: usize,
+	/// The open tags at the end of this part.
+	open: Vec<OpenTag<'a>>,
+}
+
+impl Atom<'_> {
+	fn len(&self) -> usize { self.text.chars().count() }
+}
+
+impl OpenTag<'_> {
+	/// The length of the closing tag.
+	fn close_len(&self) -> usize { self.name.chars().count() + 3 }
+}
+
+fn tokenize(s: &str) -> Vec<Atom<'_>> {
+	let mut res = Vec::new();
+	let mut rest = s;
+	while let Some(c) = rest.chars().next() {
+		let len = match c {
+			// Escaped bracket
+			'\\' if rest[1..].starts_with('[') => 2,
+			'[' => match parse_tag(rest) {
+				Some(tag) => {
+					res.push(tag);
+					rest = &rest[res.last().unwrap().text.len()..];
+					continue;
+				}
+				None => 1,
+			},
+			_ => c.len_utf8(),
+		};
+		res.push(Atom {
+			text: &rest[..len],
+			kind: Kind::Text,
+		});
+		rest = &rest[len..];
+	}
+	res
+}
+
+/// Parse a tag like `[b]`, `[/b]` or `[url=…]` at the start of `s`.
+fn parse_tag(s: &str) -> Option<Atom<'_>> {
+	let end = s.find(']')?;
+	let inner = &s[1..end];
+	let (close, inner) = match inner.strip_prefix('/') {
+		Some(i) => (true, i),
+		None => (false, inner),
+	};
+	let name = inner.split('=').next().unwrap();
+	if name.is_empty()
+		|| !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '*')
+		|| (close && name.len() != inner.len())
+		|| inner.contains('\n')
+	{
+		return None;
+	}
+	let name = name.to_ascii_lowercase();
+	Some(Atom {
+		text: &s[..=end],
+		kind: if close {
+			Kind::Close(name)
+		} else {
+			Kind::Open(name)
+		},
+	})
+}
+
+/// Apply a tag to the list of open tags.
+fn apply<'a>(open: &mut Vec<OpenTag<'a>>, atom: &Atom<'a>) {
+	match &atom.kind {
+		Kind::Text => {}
+		// `[*]` is a list item and not closed
+		Kind::Open(name) if name == "*" => {}
+		Kind::Open(name) => open.push(OpenTag {
+			text: atom.text,
+			name: name.clone(),
+		}),
+		Kind::Close(name) => {
+			if let Some(i) = open.iter().rposition(|t| t.name == *name) {
+				open.remove(i);
+			}
+		}
+	}
+}
+
+/// Stop reopening the outermost tags until at least the first atom fits into
+/// a part with the tags.
+///
+/// Otherwise many nested tags could make every part longer than `max_len`.
+fn drop_open_tags(atom: &Atom, open: &mut Vec<OpenTag>, max_len: usize) {
+	while !open.is_empty() {
+		let mut next = open.clone();
+		apply(&mut next, atom);
+		let len = open.iter().map(|t| t.text.chars().count()).sum::<usize>()
+			+ atom.len()
+			+ next.iter().map(OpenTag::close_len).sum::<usize>();
+		if len <= max_len {
+			break;
+		}
+		open.remove(0);
+	}
+}
+
+/// Find the end of a part which starts at atom `start` and fits into
+/// `max_len` characters including the tags which have to be opened and
+/// closed.
+fn part_end<'a>(
+	atoms: &[Atom<'a>],
+	start: usize,
+	open: &[OpenTag<'a>],
+	max_len: usize,
+) -> PartEnd<'a> {
+	let mut cur = open.to_vec();
+	let mut len: usize = open.iter().map(|t| t.text.chars().count()).sum();
+	let mut line_break = None;
+	let mut word_break = None;
+	let mut i = start;
+	while i < atoms.len() {
+		let atom = &atoms[i];
+		let mut next = cur.clone();
+		apply(&mut next, atom);
+		let close_len: usize = next.iter().map(OpenTag::close_len).sum();
+		if len + atom.len() + close_len > max_len {
+			break;
+		}
+		len += atom.len();
+		cur = next;
+		i += 1;
+		if atom.text == "\n" {
+			line_break = Some(i);
+		} else if atom.text == " " {
+			word_break = Some(i);
+		}
+	}
+
+	if i == atoms.len() {
+		return PartEnd {
+			end: i,
+			next: i,
+			open: cur,
+		};
+	}
+	// Prefer a line break, then a space directly at the end of the part and
+	// then the last space
+	let at_end = match atoms[i].text {
+		"\n" => Some(i + 1),
+		" " if line_break.is_none() => Some(i + 1),
+		_ => None,
+	};
+	match at_end.or(line_break).or(word_break) {
+		// Drop the line break or space
+		Some(b) if b - 1 > start => {
+			let mut open = open.to_vec();
+			for a in &atoms[start..b - 1] {
+				apply(&mut open, a);
+			}
+			PartEnd {
+				end: b - 1,
+				next: b,
+				open,
+			}
+		}
+		// At least one atom has to be in a part
+		_ if i == start => {
+			let mut open = open.to_vec();
+			apply(&mut open, &atoms[i]);
+			PartEnd {
+				end: i + 1,
+				next: i + 1,
+				open,
+			}
+		}
+		_ => PartEnd {
+			end: i,
+			next: i,
+			open: cur,
+		},
+	}
+}
+
+/// Split a message into parts of at most `max_len` characters.
+///
+/// If more than `max_parts` parts are needed, the rest is cut off.
+pub fn split_message(s: &str, max_len: usize, max_parts: usize) -> Vec<String> {
+	if s.chars().count() <= max_len {
+		return vec![s.to_string()];
+	}
+
+	let atoms = tokenize(s);
+	let mut res = Vec::new();
+	let mut start = 0;
+	let mut open = Vec::new();
+	while start < atoms.len() && res.len() < max_parts {
+		drop_open_tags(&atoms[start], &mut open, max_len);
+		let mut end = part_end(&atoms, start, &open, max_len);
+		let truncate = res.len() + 1 == max_parts && end.end < atoms.len();
+		if truncate {
+			// Make space for the ellipsis
+			let max_len = max_len.saturating_sub(ELLIPSIS.chars().count());
+			drop_open_tags(&atoms[start], &mut open, max_len);
+			end = part_end(&atoms, start, &open, max_len);
+		}
+
+		let mut part = String::new();
+		for t in &open {
+			part.push_str(t.text);
+		}
+		for a in &atoms[start..end.end] {
+			part.push_str(a.text);
+		}
+		if truncate {
+			part.push_str(ELLIPSIS);
+		}
+		for t in end.open.iter().rev() {
+			part.push_str("[/");
+			part.push_str(&t.name);
+			part.push(']');
+		}
+		res.push(part);
+		start = end.next;
+		open = end.open;
+	}
+	res
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn short() {
+		assert_eq!(split_message("abc", 3, 1), vec!["abc"]);
+	}
+
+	#[test]
+	fn lines_and_words() {
+		assert_eq!(split_message("ab cd\nef gh", 8, 5), vec!["ab cd", "ef gh"]);
+		assert_eq!(split_message("ab cd ef gh", 8, 5), vec!["ab cd ef", "gh"]);
+		assert_eq!(split_message("abcdefgh", 3, 5), vec!["abc", "def", "gh"]);
+	}
+
+	#[test]
+	fn max_parts() {
+		assert_eq!(split_message("ab cd ef gh ij", 5, 2), vec!["ab cd", "ef…"]);
+		assert_eq!(split_message("ab cd ef", 5, 2), vec!["ab cd", "ef"]);
+	}
+
+	#[test]
+	fn tags() {
+		assert_eq!(
+			split_message("[b]ab cd ef[/b]", 12, 5),
+			vec!["[b]ab cd[/b]", "[b]ef[/b]"]
+		);
+		// Tags are not split
+		assert_eq!(
+			split_message("a [URL=x]b[/URL]", 14, 5),
+			vec!["a", "[URL=x]b[/URL]"]
+		);
+		// Tags which do not fit are not reopened
+		let parts = split_message("[b][i][u]ab cd ef gh[/u][/i][/b]", 16, 10);
+		assert!(parts.iter().all(|p| p.chars().count() <= 16), "{:?}", parts);
+		assert!(parts.concat().contains("gh"), "{:?}", parts);
+		// Escaped brackets are no tags
+		assert_eq!(split_message("\\[b]ab cd", 6, 5), vec!["\\[b]ab", "cd"]);
+	}
+}
@@ -0,0 +1,268 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use base64::{prelude::BASE64_STANDARD, Engine as _};
+use regex::Regex;
+
+use crate::action::Reaction;
+use crate::Message;
+
+/// Named and numbered capture groups of the regex that triggered an action.
+///
+/// Numbered groups are stored with their index as key, so `{1}` can be
+/// looked up in the same way as `{name}`.
+pub type Captures = HashMap<String, String>;
+
+#[derive(Clone, Debug)]
+enum Part {
+	Text(String),
+	InvokerName,
+	InvokerUid,
+	InvokerId,
+	Chat,
+	Message,
+	Capture(String),
+}
+
+/// A response string with placeholders.
+///
+/// Placeholders are written in braces, e.g. `Hi {invoker.name}`. Use `{{` and
+/// `}}` for literal braces.
+#[derive(Clone, Debug)]
+pub struct Template {
+	source: String,
+	parts: Vec<Part>,
+}
+
+impl Template {
+	/// Parse a template.
+	///
+	/// `regex` is the regex of the action, its capture groups can be used as
+	/// placeholders. Unknown placeholders are an error.
+	pub fn parse(s: &str, regex: Option<&Regex>) -> Result<Self> {
+		let mut parts = Vec::new();
+		let mut text = String::new();
+		let mut chars = s.chars();
+		while let Some(c) = chars.next() {
+			match c {
+				'{' => {
+					if chars.as_str().starts_with('{') {
+						chars.next();
+						text.push('{');
+						continue;
+					}
+
+					let mut name = String::new();
+					let mut closed = false;
+					for c in chars.by_ref() {
+						if c == '}' {
+							closed = true;
+							break;
+						}
+						name.push(c);
+					}
+					if !closed {
+						bail!(
+							"Unclosed '{{' in response '{}', use '{{{{' for a \
+							 literal brace",
+							s
+						);
+					}
+
+					if !text.is_empty() {
+						parts.push(Part::Text(std::mem::take(&mut text)));
+					}
+					parts.push(Self::parse_placeholder(s, &name, regex)?);
+				}
+				'}' => {
+					if chars.as_str().starts_with('}') {
+						chars.next();
+						text.push('}');
+					} else {
+						bail!(
+							"Unmatched '}}' in response '{}', use '}}}}' for \
+							 a literal brace",
+							s
+						);
+					}
+				}
+				c => text.push(c),
+			}
+		}
+		if !text.is_empty() {
+			parts.push(Part::Text(text));
+		}
+
+		Ok(Self {
+			source: s.to_string(),
+			parts,
+		})
+	}
+
+	fn parse_placeholder(
+		s: &str,
+		name: &str,
+		regex: Option<&Regex>,
+	) -> Result<Part> {
+		Ok(match name {
+			"invoker.name" => Part::InvokerName,
+			"invoker.uid" => Part::InvokerUid,
+			"invoker.id" => Part::InvokerId,
+			"chat" => Part::Chat,
+			"message" => Part::Message,
+			_ => {
+				let known = if let Ok(i) = name.parse::<usize>() {
+					regex.map(|r| i < r.captures_len()).unwrap_or(false)
+				} else {
+					regex
+						.map(|r| r.capture_names().flatten().any(|n| n == name))
+						.unwrap_or(false)
+				};
+				if !known {
+					bail!(
+						"Unknown placeholder '{{{}}}' in response '{}', use \
+						 '{{{{' for a literal brace",
+						name,
+						s
+					);
+				}
+				Part::Capture(name.to_string())
+			}
+		})
+	}
+
+	/// The template as it was written in the settings.
+	pub fn as_str(&self) -> &str { &self.source }
+
+	/// Insert the message information into the placeholders.
+	///
+	/// Inserted values are escaped, so they cannot contain BBCode.
+	pub fn render<'a>(
+		&'a self,
+		msg: &Message,
+		captures: &Captures,
+	) -> Cow<'a, str> {
+		if let [Part::Text(s)] = self.parts.as_slice() {
+			return Cow::Borrowed(s.as_str());
+		}
+
+		let mut res = String::new();
+		for p in &self.parts {
+			match p {
+				Part::Text(s) => res.push_str(s),
+				Part::InvokerName => {
+					res.push_str(&crate::escape_bb(msg.invoker.name))
+				}
+				Part::InvokerUid => {
+					if let Some(uid) = &msg.invoker.uid {
+						res.push_str(&BASE64_STANDARD.encode(&uid.0));
+					}
+				}
+				Part::InvokerId => res.push_str(&msg.invoker.id.0.to_string()),
+				Part::Chat => res.push_str(Reaction::get_target(&msg.target)),
+				Part::Message => res.push_str(&crate::escape_bb(msg.message)),
+				Part::Capture(name) => {
+					if let Some(c) = captures.get(name) {
+						res.push_str(&crate::escape_bb(c));
+					}
+				}
+			}
+		}
+		Cow::Owned(res)
+	}
+}
+
+/// Escape braces, so `s` is a template without placeholders.
+pub fn escape(s: &str) -> String { s.replace('{', "{{").replace('}', "}}") }
+
+/// Collect all capture groups of `regex` in `s`.
+pub fn captures(regex: &Regex, s: &str, res: &mut Captures) {
+	if let Some(caps) = regex.captures(s) {
+		for (i, name) in regex.capture_names().enumerate() {
+			if let Some(m) = caps.get(i) {
+				res.insert(i.to_string(), m.as_str().to_string());
+				if let Some(name) = name {
+					res.insert(name.to_string(), m.as_str().to_string());
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tsclientlib::MessageTarget;
+
+	use super::*;
+	use crate::test_util::test_message;
+
+	fn render(s: &str, regex: Option<&str>, message: &str) -> Result<String> {
+		let regex = regex.map(|r| Regex::new(r).unwrap());
+		let template = Template::parse(s, regex.as_ref())?;
+		let mut caps = Captures::new();
+		if let Some(r) = &regex {
+			captures(r, message, &mut caps);
+		}
+		let msg = test_message(MessageTarget::Channel, message);
+		Ok(template.render(&msg, &caps).into_owned())
+	}
+
+	#[test]
+	fn text() {
+		assert_eq!(render("hello", None, "").unwrap(), "hello");
+		assert_eq!(render("", None, "").unwrap(), "");
+	}
+
+	#[test]
+	fn placeholders() {
+		assert_eq!(
+			render("Hi {invoker.name} in {chat}", None, "").unwrap(),
+			"Hi Alice in channel"
+		);
+		assert_eq!(render("{invoker.id}", None, "").unwrap(), "2");
+		assert_eq!(render("{invoker.uid}", None, "").unwrap(), "");
+		// Inserted values cannot contain BBCode
+		assert_eq!(
+			render("You said {message}", None, "[b]hi").unwrap(),
+			"You said \\[b]hi"
+		);
+	}
+
+	#[test]
+	fn braces() {
+		assert_eq!(render("{{}}", None, "").unwrap(), "{}");
+		assert_eq!(render("{{{invoker.name}}}", None, "").unwrap(), "{Alice}");
+		assert!(render("{", None, "").is_err());
+		assert!(render("{invoker.name", None, "").is_err());
+		assert!(render("}", None, "").is_err());
+		assert!(render("a } b", None, "").is_err());
+		assert_eq!(escape("{a}"), "{{a}}");
+		assert_eq!(render(&escape("{a} }{"), None, "").unwrap(), "{a} }{");
+	}
+
+	#[test]
+	fn unknown() {
+		let error = render("{foo}", None, "").unwrap_err().to_string();
+		assert!(error.contains("'{foo}'"), "{}", error);
+		assert!(error.contains("'{{'"), "{}", error);
+		assert!(render("{1}", None, "").is_err());
+		assert!(render("{1}", Some("^a$"), "a").is_err());
+		assert!(render("{name}", Some("^(?P<other>a)$"), "a").is_err());
+	}
+
+	#[test]
+	fn capture_groups() {
+		let regex = Some("^!roll (?P<count>\\d+) (\\w+)$");
+		assert_eq!(
+			render("{count} {2} ({0})", regex, "!roll 3 dice").unwrap(),
+			"3 dice (!roll 3 dice)"
+		);
+		assert_eq!(render("{1}", regex, "!roll 3 dice").unwrap(), "3");
+		// Captured values are escaped
+		assert_eq!(
+			render("{2}", Some("^!(.*) (.*)$"), "!a [b]").unwrap(),
+			"\\[b]"
+		);
+	}
+}
@@ -0,0 +1,152 @@
+//! Fixtures which are shared by the tests of all modules.
+
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{format_err, Result};
+use tsclientlib::{ChannelId, ClientId, InvokerRef, MessageTarget};
+
+use crate::connection::{BotConnection, ChannelInfo, ClientInfo};
+use crate::Message;
+
+/// An in-memory connection for tests.
+#[derive(Debug, Default)]
+pub struct FakeConnection {
+	pub own_client: Option<ClientId>,
+	pub clients: Vec<ClientInfo>,
+	pub channels: Vec<ChannelInfo>,
+	/// All messages which were sent.
+	pub sent: Vec<(MessageTarget, String)>,
+	/// All kicked clients and if they were kicked from the server.
+	pub kicked: Vec<(ClientId, bool)>,
+	pub disconnected: bool,
+}
+
+impl FakeConnection {
+	/// A connection where the bot is client 1 in channel 1.
+	pub fn new() -> Self {
+		Self {
+			own_client: Some(ClientId(1)),
+			clients: vec![ClientInfo {
+				id: ClientId(1),
+				name: "SimpleBot".into(),
+				uid: None,
+				channel: ChannelId(1),
+				server_groups: Vec::new(),
+			}],
+			channels: vec![ChannelInfo {
+				id: ChannelId(1),
+				name: "Default Channel".into(),
+				parent: ChannelId(0),
+			}],
+			..Default::default()
+		}
+	}
+}
+
+impl BotConnection for FakeConnection {
+	fn own_client(&self) -> Result<ClientId> {
+		self.own_client.ok_or_else(|| format_err!("Not connected"))
+	}
+
+	fn client(&self, id: ClientId) -> Option<ClientInfo> {
+		self.clients.iter().find(|c| c.id == id).cloned()
+	}
+
+	fn clients(&self) -> Vec<ClientInfo> { self.clients.clone() }
+
+	fn channel(&self, id: ChannelId) -> Option<ChannelInfo> {
+		self.channels.iter().find(|c| c.id == id).cloned()
+	}
+
+	fn channels(&self) -> Vec<ChannelInfo> { self.channels.clone() }
+
+	fn send_message(
+		&mut self,
+		target: MessageTarget,
+		message: &str,
+	) -> Result<()> {
+		self.sent.push((target, message.into()));
+		Ok(())
+	}
+
+	fn move_to(&mut self, channel: ChannelId) -> Result<()> {
+		let own_client = self.own_client()?;
+		let client = self
+			.clients
+			.iter_mut()
+			.find(|c| c.id == own_client)
+			.ok_or_else(|| format_err!("Own client not found"))?;
+		client.channel = channel;
+		Ok(())
+	}
+
+	fn kick(
+		&mut self,
+		client: ClientId,
+		from_server: bool,
+		_: &str,
+	) -> Result<()> {
+		self.kicked.push((client, from_server));
+		Ok(())
+	}
+
+	fn disconnect(&mut self) -> Result<()> {
+		self.disconnected = true;
+		Ok(())
+	}
+}
+
+/// The client which sends messages in tests, client 2 in channel 1.
+pub fn test_client() -> ClientInfo {
+	ClientInfo {
+		id: ClientId(2),
+		name: "Alice".into(),
+		uid: None,
+		channel: ChannelId(1),
+		server_groups: Vec::new(),
+	}
+}
+
+/// A message from the [`test_client`].
+pub fn test_message(target: MessageTarget, message: &str) -> Message<'_> {
+	Message {
+		target,
+		invoker: InvokerRef {
+			name: "Alice",
+			id: ClientId(2),
+			uid: None,
+		},
+		message,
+	}
+}
+
+/// A temporary directory for a test, which is removed when it is dropped.
+#[derive(Debug)]
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+	pub fn new(name: &str) -> Self {
+		static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+		let dir = std::env::temp_dir().join(format!(
+			"simple-bot-test-{}-{}-{}",
+			std::process::id(),
+			COUNTER.fetch_add(1, Ordering::Relaxed),
+			name
+		));
+		fs::create_dir_all(&dir).unwrap();
+		Self(dir)
+	}
+}
+
+impl Deref for TestDir {
+	type Target = Path;
+	fn deref(&self) -> &Self::Target { &self.0 }
+}
+
+impl Drop for TestDir {
+	fn drop(&mut self) { let _ = fs::remove_dir_all(&self.0); }
+}
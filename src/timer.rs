@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, format_err, Result};
+use base64::{prelude::BASE64_STANDARD, Engine as _};
+use serde::{Deserialize, Serialize};
+use tsclientlib::MessageTarget;
+
+use crate::action::{ActionDefinition, Reaction, ResponseDefinition};
+use crate::connection::BotConnection;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimerDefinition {
+	// Schedule
+	/// Run periodically, e.g. `30s`, `5m`, `2h` or `1d`.
+	pub every: Option<String>,
+	/// Run at fixed times, a cron expression with seconds, e.g.
+	/// `0 0 12 * * *` for every day at noon.
+	pub cron: Option<String>,
+
+	// Target
+	/// Where the response is sent: Either `server`, `channel` or `client`.
+	///
+	/// Defaults to `channel`.
+	pub target: Option<String>,
+	/// The name or base64 encoded uid of the client if the target is
+	/// `client`.
+	pub client: Option<String>,
+
+	// Reaction
+	/// A simple string response, a list of responses or a table of responses
+	/// and their weights.
+	pub response: Option<ResponseDefinition>,
+	/// Do not repeat the last this many responses if there are multiple
+	/// responses.
+	pub no_repeat: Option<usize>,
+	/// Execute program
+	pub command: Option<String>,
+	/// Execute command in a shell
+	pub shell: Option<String>,
+	/// Kill a command or shell after this many seconds.
+	pub timeout: Option<u64>,
+	/// Truncate the output of a command or shell to this many bytes.
+	pub max_output: Option<usize>,
+	/// The output of a command or shell is a JSON list of operations.
+	pub json_output: Option<bool>,
+}
+
+#[derive(Debug)]
+pub struct Timer {
+	pub schedule: Schedule,
+	pub target: TimerTarget,
+	pub reaction: Reaction,
+	/// When this timer runs the next time.
+	pub next: Instant,
+	/// Identifies the timer across reloads, the debug output of its
+	/// definition.
+	pub id: String,
+}
+
+#[derive(Debug)]
+pub enum Schedule {
+	Every(Duration),
+	Cron(Box<cron::Schedule>),
+}
+
+#[derive(Clone, Debug)]
+pub enum TimerTarget {
+	Server,
+	Channel,
+	/// The name or base64 encoded uid of a client.
+	Client(String),
+}
+
+impl TimerDefinition {
+	pub(crate) fn to_timer(&self) -> Result<Timer> {
+		let schedule = match (&self.every, &self.cron) {
+			(Some(every), None) => Schedule::Every(parse_duration(every)?),
+			(None, Some(cron)) => Schedule::Cron(Box::new(
+				cron::Schedule::from_str(cron).map_err(|e| {
+					format_err!("Invalid cron expression '{}': {}", cron, e)
+				})?,
+			)),
+			_ => bail!("A timer needs either every or cron"),
+		};
+
+		let target = match self.target.as_deref() {
+			Some("server") => TimerTarget::Server,
+			Some("channel") | None => TimerTarget::Channel,
+			Some("client") => match &self.client {
+				Some(c) => TimerTarget::Client(c.clone()),
+				None => bail!("A timer with target client needs a client"),
+			},
+			Some(s) => bail!(
+				"Timer target must be server, channel or client. '{}' is not \
+				 allowed.",
+				s
+			),
+		};
+		if self.client.is_some() && !matches!(target, TimerTarget::Client(_)) {
+			bail!("client can only be used with target client");
+		}
+
+		// Reuse the reaction of actions
+		let reaction = ActionDefinition {
+			response: self.response.clone(),
+			no_repeat: self.no_repeat,
+			command: self.command.clone(),
+			shell: self.shell.clone(),
+			timeout: self.timeout,
+			max_output: self.max_output,
+			json_output: self.json_output,
+			..Default::default()
+		}
+		.to_action()?
+		.reaction;
+		let reaction = match reaction {
+			Some(r) => r,
+			None => bail!("A timer needs a response, command or shell"),
+		};
+
+		let mut res = Timer {
+			schedule,
+			target,
+			reaction,
+			next: Instant::now(),
+			id: format!("{:?}", self),
+		};
+		res.next = res.schedule.next_after(Instant::now());
+		Ok(res)
+	}
+}
+
+/// Keep when timers run next if their definition did not change.
+///
+/// Otherwise every reload would start all intervals again and a timer could
+/// be pushed back forever.
+pub fn keep_next(timers: &mut [Timer], old: &[Timer]) {
+	let old = old
+		.iter()
+		.map(|t| (t.id.as_str(), t.next))
+		.collect::<HashMap<_, _>>();
+	for t in timers {
+		if let Some(next) = old.get(t.id.as_str()) {
+			t.next = *next;
+		}
+	}
+}
+
+impl Schedule {
+	/// Compute the next time after `now`.
+	pub fn next_after(&self, now: Instant) -> Instant {
+		match self {
+			Schedule::Every(d) => now + *d,
+			Schedule::Cron(c) => {
+				match c.upcoming(chrono::Local).next() {
+					Some(next) => {
+						let wait = (next - chrono::Local::now())
+							.to_std()
+							.unwrap_or_default();
+						now + wait
+					}
+					// Never run again
+					None => now + Duration::from_secs(60 * 60 * 24 * 365),
+				}
+			}
+		}
+	}
+}
+
+impl TimerTarget {
+	/// Find the target on the server.
+	///
+	/// Returns `None` if the client cannot be found.
+	pub fn resolve(&self, con: &dyn BotConnection) -> Option<MessageTarget> {
+		match self {
+			TimerTarget::Server => Some(MessageTarget::Server),
+			TimerTarget::Channel => Some(MessageTarget::Channel),
+			TimerTarget::Client(c) => con
+				.clients()
+				.into_iter()
+				.find(|client| {
+					client.name == *c
+						|| client
+							.uid
+							.as_ref()
+							.map(|u| BASE64_STANDARD.encode(&u.0) == *c)
+							.unwrap_or(false)
+				})
+				.map(|client| MessageTarget::Client(client.id)),
+		}
+	}
+}
+
+/// Parse a duration like `30s`, `5m`, `2h` or `1d`.
+///
+/// A number without unit is interpreted as seconds.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+	let s = s.trim();
+	let (num, factor) = match s.char_indices().last() {
+		Some((i, 's')) => (&s[..i], 1),
+		Some((i, 'm')) => (&s[..i], 60),
+		Some((i, 'h')) => (&s[..i], 60 * 60),
+		Some((i, 'd')) => (&s[..i], 60 * 60 * 24),
+		_ => (s, 1),
+	};
+	let num: u64 = num
+		.trim()
+		.parse()
+		.map_err(|_| format_err!("Invalid duration '{}'", s))?;
+	if num == 0 {
+		bail!("Duration must not be zero");
+	}
+	match num.checked_mul(factor) {
+		Some(secs) => Ok(Duration::from_secs(secs)),
+		None => bail!("Duration '{}' is too long", s),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_util::{FakeConnection, TestDir};
+	use crate::Bot;
+
+	#[test]
+	fn duration() {
+		assert_eq!(parse_duration("30").unwrap(), Duration::from_secs(30));
+		assert_eq!(parse_duration(" 2h ").unwrap(), Duration::from_secs(7200));
+		assert_eq!(parse_duration("1d").unwrap(), Duration::from_secs(86400));
+		assert!(parse_duration("0s").is_err());
+		assert!(parse_duration("-1m").is_err());
+		assert!(parse_duration("1w").is_err());
+		assert!(parse_duration(&format!("{}d", u64::MAX / 2)).is_err());
+	}
+
+	#[test]
+	fn without_reaction() {
+		let timer = TimerDefinition {
+			every: Some("1h".into()),
+			..Default::default()
+		};
+		assert!(timer.to_timer().is_err());
+		let timer = TimerDefinition {
+			response: Some(ResponseDefinition::Single("hi".into())),
+			..timer
+		};
+		assert!(timer.to_timer().is_ok());
+	}
+
+	#[test]
+	fn reload() {
+		let dir = TestDir::new("timer_reload");
+		let mut bot = Bot::new();
+		bot.base_dir = dir.to_path_buf();
+		bot.extra_actions = toml::from_str(
+			"[[on_timer]]\nevery = \"1h\"\nresponse = \
+			 \"tick\"\n[[on_timer]]\nevery = \"2h\"\nresponse = \"tock\"\n",
+		)
+		.unwrap();
+		crate::load_settings(&mut bot).unwrap();
+		let mut con = FakeConnection::new();
+		let mut scripts = crate::Scripts::new();
+
+		// The first timer is due, reloading does not start it over
+		let due = Instant::now();
+		bot.timers[0].next = due;
+		let next = bot.timers[1].next;
+		crate::load_settings(&mut bot).unwrap();
+		assert_eq!(bot.timers[0].next, due);
+		assert_eq!(bot.timers[1].next, next);
+		crate::run_timers(&mut bot, &mut con, &mut scripts);
+		assert_eq!(con.sent, [(MessageTarget::Channel, "tick".into())]);
+
+		// Changed timers start over
+		bot.timers[1].next = due;
+		bot.extra_actions.on_timer[1].every = Some("3h".into());
+		crate::load_settings(&mut bot).unwrap();
+		assert!(bot.timers[1].next > due);
+	}
+}
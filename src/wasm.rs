@@ -0,0 +1,556 @@
+//! Reactions which are implemented as WebAssembly plugins.
+//!
+//! Plugins run in a sandbox, they can only use the functions of the bot and
+//! are limited in the fuel (the number of executed instructions) and memory
+//! they can use.
+//!
+//! A plugin exports its `memory`, a function `alloc(len: i32) -> i32` which
+//! reserves `len` bytes and returns their address, and the handler
+//! `handle(ptr: i32, len: i32) -> i32`. The handler gets the message as JSON
+//! in the same format as commands (see [`ScriptInput`]) and returns `0` if it
+//! handled the message or another number to try the next action.
+//!
+//! The bot provides these functions in the `simplebot` module:
+//! - `send(ptr: i32, len: i32)`: Reply to the message.
+//! - `send_later(delay_ms: i64, ptr: i32, len: i32)`: Reply after some time.
+//!   A plugin can send at most [`MAX_LATER`] delayed messages per call, with
+//!   a delay of at most [`MAX_LATER_DELAY`].
+//! - `store_get(key_ptr: i32, key_len: i32, buf_ptr: i32, buf_len: i32) ->
+//!   i32`: Copy a stored value into the buffer and return its length. If the
+//!   buffer is too small, nothing is copied. Returns `-1` if the value does
+//!   not exist.
+//! - `store_set(key_ptr: i32, key_len: i32, value_ptr: i32, value_len: i32)`:
+//!   Store a value. Every plugin has its own values.
+//! - `store_remove(key_ptr: i32, key_len: i32)`: Remove a stored value.
+//! - `log(ptr: i32, len: i32)`: Write a message to the log of the bot.
+//!
+//! All strings are UTF-8.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, format_err, Result};
+use tracing::{error, info};
+use wasmi::{
+	Caller, Config, Engine, Extern, ExternType, Linker, Module, StoreLimits,
+	StoreLimitsBuilder,
+};
+
+use crate::action::{Operation, ScriptFuture, ScriptInput};
+use crate::script::{store_namespace, Store};
+use crate::Bot;
+
+/// The module of the imported functions.
+const HOST_MODULE: &str = "simplebot";
+/// The maximum number of `send_later` calls per run of a plugin.
+pub const MAX_LATER: usize = 16;
+/// The maximum delay of `send_later`.
+pub const MAX_LATER_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A loaded WebAssembly plugin.
+#[derive(Clone)]
+pub struct WasmPlugin {
+	path: PathBuf,
+	engine: Engine,
+	module: Arc<Module>,
+}
+
+/// The data of a running plugin.
+struct HostState {
+	path: String,
+	limits: StoreLimits,
+	store: Arc<Mutex<Store>>,
+	/// The namespace of the plugin in the store.
+	namespace: String,
+	operations: Vec<Operation>,
+}
+
+impl std::fmt::Debug for WasmPlugin {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "WasmPlugin({})", self.path.display())
+	}
+}
+
+impl WasmPlugin {
+	pub fn load(path: &Path) -> Result<Self> {
+		let wasm = fs::read(path).map_err(|e| {
+			format_err!("Failed to read plugin {}: {}", path.display(), e)
+		})?;
+		let mut config = Config::default();
+		config.consume_fuel(true);
+		let engine = Engine::new(&config);
+		let module = Module::new(&engine, &wasm[..]).map_err(|e| {
+			format_err!("Failed to load plugin {}: {}", path.display(), e)
+		})?;
+
+		// Check the exports early
+		for (name, is_func) in
+			[("memory", false), ("alloc", true), ("handle", true)]
+		{
+			let export = module.exports().find(|e| e.name() == name);
+			match export.map(|e| e.ty().clone()) {
+				Some(ExternType::Func(_)) if is_func => {}
+				Some(ExternType::Memory(_)) if !is_func => {}
+				_ => bail!(
+					"Plugin {} does not export the {} {}",
+					path.display(),
+					if is_func { "function" } else { "memory" },
+					name
+				),
+			}
+		}
+
+		Ok(Self {
+			path: path.into(),
+			engine,
+			module: Arc::new(module),
+		})
+	}
+
+	/// Let the plugin handle a message in a blocking task, so it does not
+	/// stop the bot.
+	///
+	/// The output are the operations the plugin wants to perform. If it is
+	/// `None`, the next action should be tried.
+	pub fn run(&self, bot: &Bot, input: ScriptInput) -> ScriptFuture {
+		let plugin = self.clone();
+		let state = HostState {
+			path: self.path.display().to_string(),
+			limits: StoreLimitsBuilder::new()
+				.memory_size(bot.settings.wasm_max_memory)
+				.build(),
+			store: bot.store.clone(),
+			namespace: store_namespace(bot, &self.path),
+			operations: Vec::new(),
+		};
+		let fuel = bot.settings.wasm_fuel;
+		Box::pin(async move {
+			let path = plugin.path.clone();
+			let run = move || plugin.run_blocking(state, fuel, &input);
+			match tokio::task::spawn_blocking(run).await {
+				Ok(r) => r,
+				Err(error) => {
+					error!(%error, plugin = %path.display(), "Failed to run plugin");
+					// Don't proceed
+					Some(Vec::new())
+				}
+			}
+		})
+	}
+
+	fn run_blocking(
+		&self,
+		state: HostState,
+		fuel: u64,
+		input: &ScriptInput,
+	) -> Option<Vec<Operation>> {
+		let store = state.store.clone();
+		let res = self.try_run(state, fuel, input);
+		if let Err(error) = store.lock().unwrap().save() {
+			error!(%error, "Failed to save the script store");
+		}
+		match res {
+			Ok(r) => r,
+			Err(error) => {
+				error!(%error, plugin = %self.path.display(), "Failed to run plugin");
+				// Don't proceed
+				Some(Vec::new())
+			}
+		}
+	}
+
+	fn try_run(
+		&self,
+		state: HostState,
+		fuel: u64,
+		input: &ScriptInput,
+	) -> Result<Option<Vec<Operation>>> {
+		let mut store = wasmi::Store::new(&self.engine, state);
+		store.limiter(|s| &mut s.limits);
+		store.set_fuel(fuel).map_err(|e| format_err!("{}", e))?;
+
+		let mut linker = Linker::new(&self.engine);
+		add_host_functions(&mut linker)?;
+		let instance = linker
+			.instantiate(&mut store, &self.module)?
+			.start(&mut store)?;
+		let memory = instance
+			.get_memory(&store, "memory")
+			.ok_or_else(|| format_err!("Memory not found"))?;
+		let alloc = instance.get_typed_func::<i32, i32>(&store, "alloc")?;
+		let handle =
+			instance.get_typed_func::<(i32, i32), i32>(&store, "handle")?;
+
+		let input = serde_json::to_vec(input)?;
+		let len = i32::try_from(input.len())?;
+		let ptr = alloc.call(&mut store, len)?;
+		memory
+			.write(&mut store, usize::try_from(ptr)?, &input)
+			.map_err(|e| format_err!("Failed to write input: {}", e))?;
+		if handle.call(&mut store, (ptr, len))? != 0 {
+			// Try the next action
+			return Ok(None);
+		}
+		Ok(Some(store.into_data().operations))
+	}
+}
+
+fn add_host_functions(linker: &mut Linker<HostState>) -> Result<()> {
+	linker.func_wrap(
+		HOST_MODULE,
+		"send",
+		|mut caller: Caller<'_, HostState>, ptr: i32, len: i32| {
+			let message = read_str(&caller, ptr, len)?;
+			caller
+				.data_mut()
+				.operations
+				.extend(Operation::from_text(message));
+			Ok(())
+		},
+	)?;
+	linker.func_wrap(
+		HOST_MODULE,
+		"send_later",
+		|mut caller: Caller<'_, HostState>, delay: i64, ptr: i32, len: i32| {
+			let message = read_str(&caller, ptr, len)?;
+			let delay = u64::try_from(delay)
+				.map_err(|_| wasmi::Error::new("Negative delay"))?;
+			let delay = Duration::from_millis(delay);
+			if delay > MAX_LATER_DELAY {
+				return Err(wasmi::Error::new("Delay is too long"));
+			}
+			let operations = &mut caller.data_mut().operations;
+			let later = operations
+				.iter()
+				.filter(|o| matches!(o, Operation::Later { .. }))
+				.count();
+			if later >= MAX_LATER {
+				return Err(wasmi::Error::new("Too many delayed messages"));
+			}
+			operations.push(Operation::Later {
+				delay,
+				operations: Operation::from_text(message),
+			});
+			Ok(())
+		},
+	)?;
+	linker.func_wrap(
+		HOST_MODULE,
+		"store_get",
+		|mut caller: Caller<'_, HostState>,
+		 key_ptr: i32,
+		 key_len: i32,
+		 buf_ptr: i32,
+		 buf_len: i32| {
+			let key = read_str(&caller, key_ptr, key_len)?;
+			let data = caller.data();
+			let value =
+				match data.store.lock().unwrap().get_str(&data.namespace, &key)
+				{
+					Some(v) => v,
+					None => return Ok(-1),
+				};
+			let len = i32::try_from(value.len())
+				.map_err(|_| wasmi::Error::new("Value too long"))?;
+			if len <= buf_len {
+				let buf = memory_slice(&mut caller, buf_ptr, len)?;
+				buf.copy_from_slice(value.as_bytes());
+			}
+			Ok(len)
+		},
+	)?;
+	linker.func_wrap(
+		HOST_MODULE,
+		"store_set",
+		|caller: Caller<'_, HostState>,
+		 key_ptr: i32,
+		 key_len: i32,
+		 value_ptr: i32,
+		 value_len: i32| {
+			let key = read_str(&caller, key_ptr, key_len)?;
+			let value = read_str(&caller, value_ptr, value_len)?;
+			let data = caller.data();
+			data.store
+				.lock()
+				.unwrap()
+				.set_str(&data.namespace, &key, value);
+			Ok(())
+		},
+	)?;
+	linker.func_wrap(
+		HOST_MODULE,
+		"store_remove",
+		|caller: Caller<'_, HostState>, ptr: i32, len: i32| {
+			let key = read_str(&caller, ptr, len)?;
+			let data = caller.data();
+			data.store.lock().unwrap().remove(&data.namespace, &key);
+			Ok(())
+		},
+	)?;
+	linker.func_wrap(
+		HOST_MODULE,
+		"log",
+		|caller: Caller<'_, HostState>, ptr: i32, len: i32| {
+			let message = read_str(&caller, ptr, len)?;
+			info!(plugin = caller.data().path.as_str(), "{}", message);
+			Ok(())
+		},
+	)?;
+	Ok(())
+}
+
+/// Get a part of the memory of a plugin.
+fn memory_slice<'a>(
+	caller: &'a mut Caller<'_, HostState>,
+	ptr: i32,
+	len: i32,
+) -> Result<&'a mut [u8], wasmi::Error> {
+	let memory = caller
+		.get_export("memory")
+		.and_then(Extern::into_memory)
+		.ok_or_else(|| wasmi::Error::new("Memory not found"))?;
+	let start = usize::try_from(ptr).ok();
+	let len = usize::try_from(len).ok();
+	start
+		.zip(len)
+		.and_then(|(start, len)| {
+			memory
+				.data_mut(caller)
+				.get_mut(start..start.checked_add(len)?)
+		})
+		.ok_or_else(|| wasmi::Error::new("Memory access out of bounds"))
+}
+
+/// Read a string from the memory of a plugin.
+fn read_str(
+	caller: &Caller<'_, HostState>,
+	ptr: i32,
+	len: i32,
+) -> Result<String, wasmi::Error> {
+	let memory = caller
+		.get_export("memory")
+		.and_then(Extern::into_memory)
+		.ok_or_else(|| wasmi::Error::new("Memory not found"))?;
+	let start = usize::try_from(ptr).ok();
+	let len = usize::try_from(len).ok();
+	let bytes = start
+		.zip(len)
+		.and_then(|(start, len)| {
+			memory.data(caller).get(start..start.checked_add(len)?)
+		})
+		.ok_or_else(|| wasmi::Error::new("Memory access out of bounds"))?;
+	String::from_utf8(bytes.to_vec())
+		.map_err(|_| wasmi::Error::new("Invalid UTF-8 string"))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Instant;
+
+	use tsclientlib::MessageTarget;
+
+	use super::*;
+	use crate::template::Captures;
+	use crate::test_util::{test_message, FakeConnection, TestDir};
+
+	/// Imports, memory and `alloc` for test plugins.
+	const PRELUDE: &str = r#"
+		(import "simplebot" "send" (func $send (param i32 i32)))
+		(import "simplebot" "send_later" (func $send_later (param i64 i32 i32)))
+		(import "simplebot" "store_set" (func $store_set (param i32 i32 i32 i32)))
+		(memory (export "memory") 1)
+		(global $next (mut i32) (i32.const 1024))
+		(func (export "alloc") (param $len i32) (result i32)
+			global.get $next
+			global.get $next
+			local.get $len
+			i32.add
+			global.set $next)
+	"#;
+
+	fn load(name: &str, wat: &str) -> Result<WasmPlugin> {
+		let wasm = wat::parse_str(format!("(module {} {})", PRELUDE, wat))?;
+		let dir = TestDir::new(name);
+		let path = dir.join("test.wasm");
+		fs::write(&path, wasm)?;
+		WasmPlugin::load(&path)
+	}
+
+	async fn run(
+		plugin: &WasmPlugin,
+		bot: &Bot,
+		message: &str,
+	) -> Option<Vec<Operation>> {
+		let con = FakeConnection::new();
+		let msg = test_message(MessageTarget::Channel, message);
+		let input = ScriptInput::new(bot, &con, &msg, &Captures::new());
+		plugin.run(bot, input).await
+	}
+
+	#[tokio::test]
+	async fn host_functions() {
+		let bot = Bot::new();
+		let plugin = load(
+			"host",
+			r#"
+			(data (i32.const 0) "Hi")
+			(data (i32.const 16) "key")
+			(data (i32.const 32) "Later")
+			(func (export "handle") (param $ptr i32) (param $len i32)
+				(result i32)
+				;; Skip if the message starts with s, the input starts with
+				;; {"target":"channel","message":"
+				local.get $ptr
+				i32.load8_u offset=31
+				i32.const 115
+				i32.eq
+				if
+					i32.const 1
+					return
+				end
+				(call $send (i32.const 0) (i32.const 2))
+				(call $store_set (i32.const 16) (i32.const 3) (i32.const 0)
+					(i32.const 2))
+				(call $send_later (i64.const 1000) (i32.const 32) (i32.const 5))
+				i32.const 0)
+			"#,
+		)
+		.unwrap();
+
+		assert_eq!(run(&plugin, &bot, "skip").await, None);
+		assert_eq!(
+			run(&plugin, &bot, "hello").await,
+			Some(vec![
+				Operation::Send {
+					target: None,
+					client: None,
+					message: "Hi".into(),
+				},
+				Operation::Later {
+					delay: Duration::from_secs(1),
+					operations: Operation::from_text("Later".into()),
+				},
+			])
+		);
+		let namespace = store_namespace(&bot, &plugin.path);
+		assert_eq!(
+			bot.store
+				.lock()
+				.unwrap()
+				.get_str(&namespace, "key")
+				.as_deref(),
+			Some("Hi")
+		);
+	}
+
+	#[tokio::test]
+	async fn limits() {
+		let bot = Bot::new();
+		// Endless loop
+		let plugin = load(
+			"fuel",
+			r#"
+			(func (export "handle") (param i32 i32) (result i32)
+				(loop $l (br $l))
+				i32.const 0)
+			"#,
+		)
+		.unwrap();
+		assert_eq!(run(&plugin, &bot, "a").await, Some(Vec::new()));
+
+		// Growing the memory fails
+		let plugin = load(
+			"memory",
+			r#"
+			(data (i32.const 0) "Grown")
+			(func (export "handle") (param i32 i32) (result i32)
+				(memory.grow (i32.const 1000))
+				i32.const -1
+				i32.ne
+				if
+					(call $send (i32.const 0) (i32.const 5))
+				end
+				i32.const 0)
+			"#,
+		)
+		.unwrap();
+		assert_eq!(run(&plugin, &bot, "a").await, Some(Vec::new()));
+
+		// Too many delayed messages
+		let plugin = load(
+			"later",
+			r#"
+			(func (export "handle") (param i32 i32) (result i32)
+				(loop $l
+					(call $send_later (i64.const 0) (i32.const 0) (i32.const 0))
+					(br $l))
+				i32.const 0)
+			"#,
+		)
+		.unwrap();
+		assert_eq!(run(&plugin, &bot, "a").await, Some(Vec::new()));
+
+		// Too long delay
+		let plugin = load(
+			"delay",
+			r#"
+			(func (export "handle") (param i32 i32) (result i32)
+				(call $send_later (i64.const 100000000000) (i32.const 0)
+					(i32.const 0))
+				i32.const 0)
+			"#,
+		)
+		.unwrap();
+		assert_eq!(run(&plugin, &bot, "a").await, Some(Vec::new()));
+	}
+
+	#[tokio::test]
+	async fn later_is_scheduled() {
+		let dir = TestDir::new("wasm_later");
+		let wasm = wat::parse_str(format!(
+			"(module {} {})",
+			PRELUDE,
+			r#"
+			(data (i32.const 0) "Hi")
+			(data (i32.const 16) "Later")
+			(func (export "handle") (param i32 i32) (result i32)
+				(call $send (i32.const 0) (i32.const 2))
+				(call $send_later (i64.const 3600000) (i32.const 16)
+					(i32.const 5))
+				i32.const 0)
+			"#
+		))
+		.unwrap();
+		fs::write(dir.join("test.wasm"), wasm).unwrap();
+		let mut bot = Bot::new();
+		bot.base_dir = dir.to_path_buf();
+		bot.extra_actions =
+			toml::from_str("[[on_message]]\nwasm = \"test.wasm\"\n").unwrap();
+		crate::load_settings(&mut bot).unwrap();
+		let mut con = FakeConnection::new();
+		let msg = test_message(MessageTarget::Channel, "hello");
+
+		// Processing the message does not wait for the delay
+		let timeout = Duration::from_secs(10);
+		tokio::time::timeout(timeout, bot.process_message(&mut con, &msg))
+			.await
+			.unwrap();
+		assert_eq!(con.sent, [(MessageTarget::Channel, "Hi".into())]);
+		assert!(bot.next_delayed().unwrap() > Instant::now());
+
+		bot.process_delayed(&mut con).await;
+		assert_eq!(con.sent.len(), 1);
+		bot.delayed[0].at = Instant::now();
+		bot.process_delayed(&mut con).await;
+		assert_eq!(con.sent[1], (MessageTarget::Channel, "Later".into()));
+		assert_eq!(bot.next_delayed(), None);
+	}
+
+	#[test]
+	fn missing_export() {
+		let error = load("export", "").unwrap_err();
+		assert!(error.to_string().contains("handle"), "{}", error);
+	}
+}
@@ -0,0 +1,296 @@
+//! Reload the settings when one of the files they were loaded from changes.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use futures::channel::mpsc;
+use futures::prelude::*;
+use notify_debouncer_mini::notify::{RecommendedWatcher, RecursiveMode};
+use notify_debouncer_mini::{
+	new_debouncer, DebounceEventResult, DebouncedEvent, Debouncer,
+};
+use tracing::{debug, warn};
+
+use crate::Bot;
+
+/// How long to wait for more changes before reloading.
+///
+/// Editors often write a file in multiple steps, it should only be reloaded
+/// once.
+const DEBOUNCE_TIME: Duration = Duration::from_millis(500);
+
+/// The modification time and size of a file, `None` if it does not exist.
+pub type FileVersion = Option<(SystemTime, u64)>;
+
+/// Watches files and notifies about changes.
+///
+/// Directories are watched instead of the files themselves, so files which
+/// are replaced or do not exist yet are noticed as well.
+pub struct FileWatcher {
+	debouncer: Debouncer<RecommendedWatcher>,
+	/// The files which were passed to `watch` the last time.
+	paths: Vec<PathBuf>,
+	/// The watched directories.
+	dirs: HashSet<PathBuf>,
+	/// The watched files, with canonical directories.
+	files: Arc<Mutex<HashSet<PathBuf>>>,
+	changes: mpsc::UnboundedReceiver<()>,
+}
+
+impl FileWatcher {
+	pub fn new() -> Result<Self> {
+		let files = Arc::new(Mutex::new(HashSet::<PathBuf>::new()));
+		let (send, changes) = mpsc::unbounded();
+		let watched = files.clone();
+		let debouncer =
+			new_debouncer(DEBOUNCE_TIME, move |res: DebounceEventResult| {
+				match res {
+					Ok(events) => {
+						let watched = watched.lock().unwrap();
+						if let Some(path) = changed_file(&watched, &events) {
+							debug!(?path, "Watched file changed");
+							let _ = send.unbounded_send(());
+						}
+					}
+					Err(error) => warn!(%error, "Failed to watch files"),
+				}
+			})?;
+		Ok(Self {
+			debouncer,
+			paths: Vec::new(),
+			dirs: HashSet::new(),
+			files,
+			changes,
+		})
+	}
+
+	/// Watch these files and stop watching all other files.
+	pub fn watch(&mut self, files: &[PathBuf]) {
+		if self.paths == files {
+			return;
+		}
+		self.paths = files.to_vec();
+		let mut dirs = HashSet::new();
+		let mut watched = HashSet::new();
+		for f in files {
+			match canonical_file(f) {
+				Some(f) => {
+					dirs.insert(f.parent().unwrap().to_path_buf());
+					watched.insert(f);
+				}
+				None => {
+					debug!(file = ?f, "Not watching file without a directory")
+				}
+			}
+		}
+		*self.files.lock().unwrap() = watched;
+
+		let watcher = self.debouncer.watcher();
+		for d in self.dirs.difference(&dirs) {
+			if let Err(error) = watcher.unwatch(d) {
+				warn!(%error, dir = ?d, "Failed to stop watching directory");
+			}
+		}
+		for d in dirs.difference(&self.dirs) {
+			debug!(dir = ?d, "Watching directory");
+			if let Err(error) = watcher.watch(d, RecursiveMode::NonRecursive) {
+				warn!(%error, dir = ?d, "Failed to watch directory");
+			}
+		}
+		self.dirs = dirs;
+	}
+
+	/// Wait until a watched file changes.
+	pub async fn changed(&mut self) {
+		if self.changes.next().await.is_none() {
+			// The watcher stopped, wait forever
+			future::pending::<()>().await;
+		}
+		// Multiple changes need only one reload
+		while self.changes.try_recv().is_ok() {}
+	}
+}
+
+/// Wait until a watched file changes, never returns without a watcher.
+pub async fn changed(watcher: &mut Option<FileWatcher>) {
+	match watcher {
+		Some(w) => w.changed().await,
+		None => future::pending().await,
+	}
+}
+
+/// Watch the files which the settings of the bot were loaded from.
+pub fn update(watcher: &mut Option<FileWatcher>, bot: &Bot) {
+	if let Some(w) = watcher {
+		w.watch(&bot.files);
+	}
+}
+
+/// Reload the settings if one of their files changed since it was loaded.
+///
+/// The bot loads the files again after writing them itself, e.g. the dynamic
+/// actions, so its own changes do not cause another reload.
+pub fn reload(watcher: &mut Option<FileWatcher>, bot: &mut Bot) {
+	if is_modified(&bot.files, &bot.file_versions) {
+		bot.should_reload.set(true);
+		crate::check_reload(bot);
+	} else {
+		debug!("Watched files did not change since they were loaded");
+	}
+	update(watcher, bot);
+}
+
+pub fn file_version(path: &Path) -> FileVersion {
+	let metadata = fs::metadata(path).ok()?;
+	Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// If one of the `files` is not at the version it had when it was loaded.
+fn is_modified(files: &[PathBuf], versions: &[FileVersion]) -> bool {
+	files.len() != versions.len()
+		|| files
+			.iter()
+			.zip(versions)
+			.any(|(f, v)| file_version(f) != *v)
+}
+
+/// The first of the changed files which is watched.
+fn changed_file<'a>(
+	watched: &HashSet<PathBuf>,
+	events: &'a [DebouncedEvent],
+) -> Option<&'a Path> {
+	events
+		.iter()
+		.map(|e| e.path.as_path())
+		.find(|p| watched.contains(*p))
+}
+
+/// The path of a file in its canonical directory.
+///
+/// The file itself does not need to exist.
+fn canonical_file(path: &Path) -> Option<PathBuf> {
+	let name = path.file_name()?;
+	let dir = match path.parent() {
+		Some(d) if !d.as_os_str().is_empty() => d,
+		_ => Path::new("."),
+	};
+	Some(fs::canonicalize(dir).ok()?.join(name))
+}
+
+#[cfg(test)]
+mod tests {
+	use notify_debouncer_mini::DebouncedEventKind;
+
+	use super::*;
+	use crate::test_util::TestDir;
+
+	#[test]
+	fn watch() {
+		let dir = TestDir::new("watch");
+		let sub = dir.join("sub");
+		fs::create_dir_all(&sub).unwrap();
+		let canonical = fs::canonicalize(&*dir).unwrap();
+
+		let mut watcher = FileWatcher::new().unwrap();
+		let files = [dir.join("a.toml"), sub.join("b.toml")];
+		watcher.watch(&files);
+		assert_eq!(
+			watcher.dirs,
+			HashSet::from([canonical.clone(), canonical.join("sub")])
+		);
+		assert_eq!(
+			*watcher.files.lock().unwrap(),
+			HashSet::from([
+				canonical.join("a.toml"),
+				canonical.join("sub/b.toml")
+			])
+		);
+
+		// Directories without watched files are not watched anymore
+		watcher.watch(&files[..1]);
+		assert_eq!(watcher.dirs, HashSet::from([canonical.clone()]));
+		assert_eq!(
+			*watcher.files.lock().unwrap(),
+			HashSet::from([canonical.join("a.toml")])
+		);
+	}
+
+	#[test]
+	fn changed_files() {
+		let watched = HashSet::from([PathBuf::from("/bot/settings.toml")]);
+		let event = |path: &str| DebouncedEvent {
+			path: path.into(),
+			kind: DebouncedEventKind::Any,
+		};
+		// Other files in the directory are ignored
+		assert_eq!(changed_file(&watched, &[event("/bot/other.toml")]), None);
+		assert_eq!(
+			changed_file(
+				&watched,
+				&[event("/bot/other.toml"), event("/bot/settings.toml")]
+			),
+			Some(Path::new("/bot/settings.toml"))
+		);
+	}
+
+	#[tokio::test]
+	async fn changed() {
+		let mut watcher = FileWatcher::new().unwrap();
+		let (send, changes) = mpsc::unbounded();
+		watcher.changes = changes;
+		assert!(watcher.changed().now_or_never().is_none());
+
+		// Multiple changes need only one reload
+		for _ in 0..3 {
+			send.unbounded_send(()).unwrap();
+		}
+		assert!(watcher.changed().now_or_never().is_some());
+		assert!(watcher.changed().now_or_never().is_none());
+	}
+
+	#[test]
+	fn own_changes() {
+		let dir = TestDir::new("own_changes");
+		let path = dir.join("dynamic.toml");
+		let mut bot = Bot::new();
+		bot.base_dir = dir.to_path_buf();
+		// Old dynamic actions are escaped and saved when they are loaded
+		fs::write(
+			&path,
+			"[[on_message]]\ncontains = \"a\"\nresponse = \"{b}\"\n",
+		)
+		.unwrap();
+		crate::load_settings(&mut bot).unwrap();
+		assert!(fs::read_to_string(&path).unwrap().contains("{{b}}"));
+		let generation = bot.generation;
+		reload(&mut None, &mut bot);
+		assert_eq!(bot.generation, generation);
+
+		// Changes by others are reloaded
+		fs::write(&path, "").unwrap();
+		reload(&mut None, &mut bot);
+		assert_eq!(bot.generation, generation + 1);
+	}
+
+	#[test]
+	fn modified() {
+		let dir = TestDir::new("modified");
+		let file = dir.join("settings.toml");
+		let files = [file.clone()];
+		let versions = [file_version(&file)];
+		assert_eq!(versions, [None]);
+		assert!(!is_modified(&files, &versions));
+
+		fs::write(&file, "a").unwrap();
+		assert!(is_modified(&files, &versions));
+		let versions = [file_version(&file)];
+		assert!(!is_modified(&files, &versions));
+		// The size changes, even if the modification time is the same
+		fs::write(&file, "ab").unwrap();
+		assert!(is_modified(&files, &versions));
+	}
+}
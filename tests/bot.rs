@@ -0,0 +1,327 @@
+use std::borrow::Cow;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use simple_bot::connection::{
+	BotConnection, BotEvent, ChannelInfo, ClientInfo,
+};
+use simple_bot::plugin::{Command, Plugin};
+use simple_bot::{ActionFile, Bot, Message, Settings};
+use tsclientlib::{
+	ChannelId, ClientId, Invoker, InvokerRef, MessageTarget, ServerGroupId,
+};
+
+/// A temporary directory which is removed when it is dropped.
+struct TestDir(PathBuf);
+
+impl TestDir {
+	fn new(name: &str) -> Self {
+		let dir = std::env::temp_dir().join(format!(
+			"simple-bot-test-{}-{}",
+			std::process::id(),
+			name
+		));
+		std::fs::create_dir_all(&dir).unwrap();
+		Self(dir)
+	}
+}
+
+impl Deref for TestDir {
+	type Target = Path;
+	fn deref(&self) -> &Self::Target { &self.0 }
+}
+
+impl Drop for TestDir {
+	fn drop(&mut self) { let _ = std::fs::remove_dir_all(&self.0); }
+}
+
+/// A connection which remembers sent messages.
+#[derive(Default)]
+struct Recorder {
+	sent: Vec<(MessageTarget, String)>,
+}
+
+impl BotConnection for Recorder {
+	fn own_client(&self) -> Result<ClientId> { Ok(ClientId(1)) }
+	fn client(&self, _: ClientId) -> Option<ClientInfo> { None }
+	fn clients(&self) -> Vec<ClientInfo> { Vec::new() }
+	fn channel(&self, _: ChannelId) -> Option<ChannelInfo> { None }
+	fn channels(&self) -> Vec<ChannelInfo> { Vec::new() }
+
+	fn send_message(
+		&mut self,
+		target: MessageTarget,
+		message: &str,
+	) -> Result<()> {
+		self.sent.push((target, message.into()));
+		Ok(())
+	}
+
+	fn move_to(&mut self, _: ChannelId) -> Result<()> { bail!("Not supported") }
+
+	fn kick(&mut self, _: ClientId, _: bool, _: &str) -> Result<()> {
+		bail!("Not supported")
+	}
+
+	fn disconnect(&mut self) -> Result<()> { Ok(()) }
+}
+
+struct Echo;
+
+impl Plugin for Echo {
+	fn name(&self) -> &str { "echo" }
+
+	fn commands(&self, bot: &Bot) -> Vec<Command> {
+		vec![Command {
+			name: "echo".into(),
+			regex: regex::Regex::new(&format!(
+				"^{}echo ",
+				regex::escape(&bot.settings().prefix)
+			))
+			.unwrap(),
+			restricted: true,
+		}]
+	}
+
+	fn run_command<'a>(
+		&self,
+		_: &Bot,
+		_: &mut dyn BotConnection,
+		_: &str,
+		msg: &'a Message,
+	) -> Option<Cow<'a, str>> {
+		Some(msg.message[6..].into())
+	}
+}
+
+async fn send(bot: &mut Bot, con: &mut Recorder, message: &str) -> Vec<String> {
+	let msg = Message {
+		target: MessageTarget::Channel,
+		invoker: InvokerRef {
+			name: "Alice",
+			id: ClientId(2),
+			uid: None,
+		},
+		message,
+	};
+	bot.process_message(con, &msg).await;
+	con.sent.drain(..).map(|(_, m)| m).collect()
+}
+
+#[tokio::test]
+async fn builder() {
+	let dir = TestDir::new("builder");
+	let mut settings = Settings::default();
+	settings.prefix = "!".into();
+	settings.rate_limit = 100;
+	let actions: ActionFile = toml::from_str(
+		r#"
+		[[on_message]]
+		contains = "hello"
+		response = "Hi {invoker.name}"
+		"#,
+	)
+	.unwrap();
+	let mut bot = Bot::builder()
+		.base_dir(dir.to_path_buf())
+		.settings(settings)
+		.actions(actions)
+		.plugin(Echo)
+		.build()
+		.unwrap();
+
+	let mut con = Recorder::default();
+	assert_eq!(send(&mut bot, &mut con, "hello there").await, ["Hi Alice"]);
+	assert_eq!(send(&mut bot, &mut con, "!echo ping").await, ["ping"]);
+	assert!(send(&mut bot, &mut con, "!help").await[0].contains("!add"));
+	assert!(send(&mut bot, &mut con, "nothing").await.is_empty());
+}
+
+#[tokio::test]
+async fn ignore_messages() {
+	let dir = TestDir::new("ignore");
+	let mut settings = Settings::default();
+	settings.rate_limit = 1;
+	let actions: ActionFile = toml::from_str(
+		"[[on_message]]\ncontains = \"hello\"\nresponse = \"Hi\"\n",
+	)
+	.unwrap();
+	let mut bot = Bot::builder()
+		.base_dir(dir.to_path_buf())
+		.settings(settings)
+		.actions(actions)
+		.build()
+		.unwrap();
+
+	// Messages of the bot itself
+	let mut con = Recorder::default();
+	let msg = Message {
+		target: MessageTarget::Channel,
+		invoker: InvokerRef {
+			name: "SimpleBot",
+			id: ClientId(1),
+			uid: None,
+		},
+		message: "hello",
+	};
+	bot.process_message(&mut con, &msg).await;
+	assert!(con.sent.is_empty());
+
+	// Messages which hit the rate limit
+	assert_eq!(send(&mut bot, &mut con, "hello").await, ["Hi"]);
+	assert!(send(&mut bot, &mut con, "hello").await.is_empty());
+}
+
+#[tokio::test]
+async fn keep_config_on_error() {
+	let dir = TestDir::new("keep-config");
+	let settings = dir.join("settings.toml");
+	std::fs::write(&settings, "[actions]\ninclude = [\"more.toml\"]\n")
+		.unwrap();
+	std::fs::write(
+		dir.join("more.toml"),
+		"[[on_message]]\ncontains = \"foo\"\nresponse = \"bar\"\n",
+	)
+	.unwrap();
+	let mut bot = Bot::builder().settings_file(&settings).build().unwrap();
+
+	let mut con = Recorder::default();
+	assert_eq!(send(&mut bot, &mut con, "foo").await, ["bar"]);
+	std::fs::write(dir.join("more.toml"), "[[on_message]]\ncontains =")
+		.unwrap();
+	assert!(send(&mut bot, &mut con, ".reload").await.is_empty());
+	assert_eq!(send(&mut bot, &mut con, "foo").await, ["bar"]);
+}
+
+#[tokio::test]
+async fn run_with() {
+	let dir = TestDir::new("run-with");
+	std::fs::write(dir.join("reply.rhai"), "reply(`Script ${message}`);")
+		.unwrap();
+	let mut settings = Settings::default();
+	settings.rate_limit = 100;
+	settings.watch_files = false;
+	let actions: ActionFile = toml::from_str(
+		r#"
+		[[on_message]]
+		contains = "hello"
+		response = "Hi {invoker.name}"
+
+		[[on_message]]
+		contains = "script"
+		script = "reply.rhai"
+
+		[[on_join]]
+		response = "Welcome {invoker.name}"
+		"#,
+	)
+	.unwrap();
+	let mut bot = Bot::builder()
+		.base_dir(dir.to_path_buf())
+		.settings(settings)
+		.actions(actions)
+		.build()
+		.unwrap();
+
+	let alice = ClientInfo {
+		id: ClientId(2),
+		name: "Alice".into(),
+		uid: None,
+		channel: ChannelId(1),
+		server_groups: Vec::new(),
+	};
+	let message = |message: &str| BotEvent::Message {
+		target: MessageTarget::Channel,
+		invoker: Invoker {
+			name: "Alice".into(),
+			id: ClientId(2),
+			uid: None,
+		},
+		message: message.into(),
+	};
+	let events = futures::stream::iter(vec![
+		BotEvent::Join(alice),
+		message("hello"),
+		message("script"),
+	]);
+	let mut con = Recorder::default();
+	bot.run_with(&mut con, events).await.unwrap();
+
+	// Scripts run in the background, so their response can come later
+	let mut sent = con.sent.into_iter().map(|(_, m)| m).collect::<Vec<_>>();
+	sent.sort();
+	assert_eq!(sent, ["Hi Alice", "Script script", "Welcome Alice"]);
+}
+
+#[tokio::test]
+async fn leave_with_server_group() {
+	let dir = TestDir::new("leave");
+	let mut settings = Settings::default();
+	settings.rate_limit = 100;
+	let actions: ActionFile = toml::from_str(
+		r#"
+		[[on_leave]]
+		from_server_group = [6]
+		response = "Bye {invoker.name}"
+		"#,
+	)
+	.unwrap();
+	let mut bot = Bot::builder()
+		.base_dir(dir.to_path_buf())
+		.settings(settings)
+		.actions(actions)
+		.build()
+		.unwrap();
+
+	// The connection does not know the clients anymore
+	let client = |id, name: &str, groups| ClientInfo {
+		id: ClientId(id),
+		name: name.into(),
+		uid: None,
+		channel: ChannelId(1),
+		server_groups: groups,
+	};
+	let events = futures::stream::iter(vec![
+		BotEvent::Leave(client(2, "Alice", vec![ServerGroupId(6)])),
+		BotEvent::Leave(client(3, "Bob", vec![ServerGroupId(7)])),
+	]);
+	let mut con = Recorder::default();
+	bot.run_with(&mut con, events).await.unwrap();
+	assert_eq!(con.sent, [(MessageTarget::Server, "Bye Alice".into())]);
+}
+
+#[tokio::test]
+async fn start_with_broken_include() {
+	let dir = TestDir::new("broken-include");
+	let settings = dir.join("settings.toml");
+	std::fs::write(
+		&settings,
+		"[actions]\ninclude = \
+		 [\"more.toml\"]\n[[actions.on_message]]\ncontains = \
+		 \"foo\"\nresponse = \"bar\"\n",
+	)
+	.unwrap();
+	std::fs::write(dir.join("more.toml"), "[[on_message]]\ncontains =")
+		.unwrap();
+	// The bot starts with the actions which could be loaded
+	let mut bot = Bot::builder().settings_file(&settings).build().unwrap();
+
+	let mut con = Recorder::default();
+	assert_eq!(send(&mut bot, &mut con, "foo").await, ["bar"]);
+
+	// Also with malformed dynamic actions
+	let dynamic = dir.join("dynamic.toml");
+	std::fs::write(&dynamic, "[[on_message]]\ncontains =").unwrap();
+	let mut bot = Bot::builder().settings_file(&settings).build().unwrap();
+	assert_eq!(send(&mut bot, &mut con, "foo").await, ["bar"]);
+
+	// And with a dynamic action which cannot be loaded, the file is not
+	// rewritten then
+	let content = "[[on_message]]\ncontains = \"old\"\nresponse = \
+	               \"{\"\n[[on_message]]\nregex = \"(\"\nresponse = \"x\"\n";
+	std::fs::write(&dynamic, content).unwrap();
+	let mut bot = Bot::builder().settings_file(&settings).build().unwrap();
+	assert_eq!(send(&mut bot, &mut con, "foo").await, ["bar"]);
+	assert_eq!(std::fs::read_to_string(&dynamic).unwrap(), content);
+}